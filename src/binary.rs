@@ -0,0 +1,582 @@
+use std::{
+    io::{Cursor, Read, Seek, SeekFrom, Write},
+    time::Duration,
+};
+
+use binrw::{binrw, BinRead, BinResult, BinWrite, Endian};
+use modular_bitfield::{bitfield, prelude::B5};
+
+use crate::{
+    buttons::{ButtonState, MouseButton},
+    error::Error,
+    layout::{self, Action, Layout, MouseAction, Settings, SettingsChanges},
+};
+
+// Commands, command lists and header settings are the same in v6 and v7 configs,
+// only the padding, flag bits and button bits are laid out differently
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+#[binrw]
+#[brw(big, repr = u8)]
+pub enum CommandType {
+    None = 0,
+    System = 1,
+    Keyboard = 2,
+    Mouse = 3,
+    Delay = 5,
+    ListOfCommands = 7,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[binrw]
+pub struct HidCommand {
+    pub modifier: u8,
+    pub key_code: u8,
+}
+
+#[bitfield]
+#[derive(BinRead, BinWrite, Debug, Copy, Clone, PartialEq, Default)]
+#[br(map = Self::from_bytes)]
+pub struct MouseButtons {
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+    pub unknown: B5,
+}
+
+impl From<MouseButton> for MouseButtons {
+    fn from(button: MouseButton) -> Self {
+        MouseButtons::new()
+            .with_left(button == MouseButton::Left)
+            .with_middle(button == MouseButton::Middle)
+            .with_right(button == MouseButton::Right)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[binrw]
+pub struct MouseCommand {
+    pub buttons: MouseButtons,
+    #[br(map = |action: u8| MouseAction::from(action))]
+    #[bw(map = |action: &MouseAction| u8::from(*action))]
+    pub action: MouseAction,
+}
+
+impl MouseCommand {
+    pub fn click(buttons: MouseButtons) -> Self {
+        Self {
+            buttons,
+            action: MouseAction::Click,
+        }
+    }
+
+    /// A click with no buttons lets go of everything, ending a press in a command list
+    pub fn is_release(&self) -> bool {
+        self.action == MouseAction::Click && self.buttons == MouseButtons::new()
+    }
+}
+
+impl From<&MouseCommand> for layout::Mouse {
+    fn from(command: &MouseCommand) -> Self {
+        layout::Mouse {
+            left: command.buttons.left(),
+            middle: command.buttons.middle(),
+            right: command.buttons.right(),
+            action: command.action,
+        }
+    }
+}
+
+impl From<&layout::Mouse> for MouseCommand {
+    fn from(mouse: &layout::Mouse) -> Self {
+        MouseCommand {
+            buttons: MouseButtons::new()
+                .with_left(mouse.left)
+                .with_middle(mouse.middle)
+                .with_right(mouse.right),
+            action: mouse.action,
+        }
+    }
+}
+
+/// A pause, mostly useful inside command lists. Stored in 10ms steps
+/// so the longest is a little under 11 minutes
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[binrw]
+pub struct DelayCommand {
+    ticks: u16,
+}
+
+impl DelayCommand {
+    pub const MAX_MILLIS: u32 = u16::MAX as u32 * 10;
+
+    pub fn from_millis(millis: u32) -> Result<Self, Error> {
+        if millis > Self::MAX_MILLIS {
+            return Err(Error::InvalidValue(format!(
+                "Delay of {}ms is longer than the maximum of {}ms",
+                millis,
+                Self::MAX_MILLIS
+            )));
+        }
+        if !millis.is_multiple_of(10) {
+            return Err(Error::InvalidValue(format!(
+                "Delay of {}ms isn't a multiple of 10ms",
+                millis
+            )));
+        }
+
+        Ok(Self {
+            ticks: (millis / 10) as u16,
+        })
+    }
+
+    pub fn millis(&self) -> u32 {
+        self.ticks as u32 * 10
+    }
+}
+
+/// A v6 or v7 command, they hold the same things with different padding
+pub trait ListCommand:
+    Sized + PartialEq + for<'a> BinRead<Args<'a> = ()> + for<'a> BinWrite<Args<'a> = ()>
+{
+    /// A None command, which ends a command list
+    fn is_end(&self) -> bool;
+
+    /// Where the command list is when the command runs one
+    fn list_offset(&self) -> Option<u16>;
+
+    /// Runs the command list at `offset`
+    fn list_at(offset: u16) -> Self;
+
+    /// What the command does, command lists are read by `to_layout` since
+    /// they can't be nested
+    fn action(&self) -> Action;
+
+    /// A command for an action, strings only get their `ListOfCommands` command,
+    /// their contents go in the command lists
+    fn from_action(action: &Action) -> Result<Self, Error>;
+}
+
+/// A v6 or v7 chord, they lay out their button bits differently
+pub trait ListChord {
+    type Command: ListCommand;
+
+    fn new(buttons: ButtonState, command: Self::Command) -> Self;
+    fn button_state(&self) -> ButtonState;
+    fn command(&self) -> &Self::Command;
+    fn command_mut(&mut self) -> &mut Self::Command;
+}
+
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct CommandList<C>(pub Vec<C>);
+
+impl<C: ListCommand> BinRead for CommandList<C> {
+    type Args<'a> = ();
+
+    fn read_options<R: Read + Seek>(
+        reader: &mut R,
+        endian: Endian,
+        (): Self::Args<'_>,
+    ) -> BinResult<Self> {
+        let mut values = vec![];
+
+        loop {
+            let command = C::read_options(reader, endian, ())?;
+            if command.is_end() {
+                return Ok(Self(values));
+            }
+            values.push(command);
+        }
+    }
+}
+
+impl<C: ListCommand> BinWrite for CommandList<C> {
+    type Args<'a> = ();
+
+    fn write_options<W: Write + Seek>(
+        &self,
+        writer: &mut W,
+        endian: Endian,
+        args: Self::Args<'_>,
+    ) -> BinResult<()> {
+        for command in &self.0 {
+            command.write_options(writer, endian, args)?;
+        }
+        0u32.write_options(writer, endian, args)?;
+
+        Ok(())
+    }
+}
+
+/// Command lists are stored after the chords and found through the offset in
+/// each list chord, so chords can share a list and lists can be in any order
+#[binrw::parser(reader, endian)]
+pub(crate) fn read_command_lists<C: ListChord>(
+    chords: &[C],
+) -> BinResult<Vec<CommandList<C::Command>>> {
+    let base = reader.stream_position()?;
+    let mut end = base;
+
+    let mut command_lists = vec![];
+    for chord in chords {
+        if let Some(offset) = chord.command().list_offset() {
+            reader.seek(SeekFrom::Start(base + offset as u64))?;
+            command_lists.push(CommandList::read_options(reader, endian, ())?);
+            end = end.max(reader.stream_position()?);
+        }
+    }
+
+    reader.seek(SeekFrom::Start(end))?;
+    Ok(command_lists)
+}
+
+/// Write each list at the offset its chord points to, lists shared by several
+/// chords are only written once
+#[binrw::writer(writer, endian)]
+pub(crate) fn write_command_lists<C: ListChord>(
+    command_lists: &Vec<CommandList<C::Command>>,
+    chords: &[C],
+) -> BinResult<()> {
+    let base = writer.stream_position()?;
+    let mut end = base;
+
+    let offsets = chords
+        .iter()
+        .filter_map(|chord| chord.command().list_offset())
+        .collect::<Vec<_>>();
+    if offsets.len() != command_lists.len() {
+        return Err(binrw::Error::AssertFail {
+            pos: base,
+            message: format!(
+                "{} list chords but {} command lists",
+                offsets.len(),
+                command_lists.len()
+            ),
+        });
+    }
+
+    let mut written: Vec<(u16, &CommandList<C::Command>)> = vec![];
+    for (offset, list) in offsets.into_iter().zip(command_lists) {
+        if let Some((_, other)) = written.iter().find(|(o, _)| *o == offset) {
+            if *other != list {
+                return Err(binrw::Error::AssertFail {
+                    pos: base + offset as u64,
+                    message: format!(
+                        "chords sharing the command list at offset {:#x} have different lists",
+                        offset
+                    ),
+                });
+            }
+            continue;
+        }
+
+        writer.seek(SeekFrom::Start(base + offset as u64))?;
+        list.write_options(writer, endian, ())?;
+        end = end.max(writer.stream_position()?);
+        written.push((offset, list));
+    }
+
+    writer.seek(SeekFrom::Start(end))?;
+    Ok(())
+}
+
+/// The settings in a v6 or v7 header, in the units they're stored in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Header {
+    pub key_repeat: bool,
+    pub haptic: bool,
+    pub direct: bool,
+    pub sticky_num: bool,
+    pub sticky_alt: bool,
+    pub sticky_ctrl: bool,
+    pub sticky_shift: bool,
+    pub left_mouse_pos: bool,
+    /// Seconds before the device sleeps
+    pub idle_time: u16,
+    /// Mouse speed as shown on the device, 1 is slowest and 254 fastest
+    pub mouse_speed: u8,
+    /// Key repeat delay in 10ms steps
+    pub key_repeat_delay: u8,
+    pub mouse_accel_table: [u8; 20],
+}
+
+/// A v6 or v7 config, everything but the header layout and the chord and
+/// command types is shared
+pub trait BinaryConfig {
+    type Command: ListCommand;
+    type Chord: ListChord<Command = Self::Command>;
+
+    /// The version's name in warnings, like "v7"
+    const NAME: &'static str;
+
+    fn header(&self) -> Header;
+
+    /// Store the settings in `header`, flag bits and bytes it doesn't cover are kept
+    fn set_header(&mut self, header: &Header);
+
+    fn chords(&self) -> &[Self::Chord];
+
+    fn command_lists(&self) -> &[CommandList<Self::Command>];
+
+    /// Take the chords and command lists out to change them together
+    fn take_chord_table(&mut self) -> (Vec<Self::Chord>, Vec<CommandList<Self::Command>>);
+
+    /// Put the chords and command lists back, updating the chord count
+    fn set_chord_table(
+        &mut self,
+        chords: Vec<Self::Chord>,
+        command_lists: Vec<CommandList<Self::Command>>,
+    );
+
+    /// Change the settings in `changes` and nothing else, unknown flag bits
+    /// and bytes are kept for lossless writes
+    fn apply_settings(&mut self, changes: &SettingsChanges) -> Result<(), Error> {
+        changes.validate()?;
+
+        let mut header = self.header();
+        let flags = [
+            (changes.key_repeat, &mut header.key_repeat),
+            (changes.haptic, &mut header.haptic),
+            (changes.direct, &mut header.direct),
+            (changes.sticky_num, &mut header.sticky_num),
+            (changes.sticky_alt, &mut header.sticky_alt),
+            (changes.sticky_ctrl, &mut header.sticky_ctrl),
+            (changes.sticky_shift, &mut header.sticky_shift),
+            (changes.left_mouse_pos, &mut header.left_mouse_pos),
+        ];
+        for (change, flag) in flags {
+            if let Some(on) = change {
+                *flag = on;
+            }
+        }
+
+        if let Some(idle_time) = changes.idle_time {
+            header.idle_time = idle_time.as_secs() as u16;
+        }
+        if let Some(speed) = changes.mouse_speed {
+            header.mouse_speed = speed;
+        }
+        if let Some(delay) = changes.repeat_delay {
+            header.key_repeat_delay = (delay.as_millis() / 10) as u8;
+        }
+        self.set_header(&header);
+
+        Ok(())
+    }
+}
+
+/// The most chords the format can count, the firmware's own limits aren't documented
+pub const MAX_CHORDS: usize = u16::MAX as usize;
+/// Command lists are found through u16 offsets, so they're kept inside the range
+/// those offsets reach
+pub const MAX_COMMAND_LIST_BYTES: usize = u16::MAX as usize + 1;
+/// The largest file written. The firmware doesn't document a limit, this is far
+/// above any real layout and stops runaway ones before they reach the device
+pub const MAX_FILE_SIZE: usize = 256 * 1024;
+
+fn limit_error(message: String) -> Error {
+    Error::Write(message)
+}
+
+/// Write a config with its chord count and command list offsets filled in,
+/// identical lists are only stored once. Returns how much that saved, when it did
+pub(crate) fn write<C, W>(mut config: C, writer: &mut W) -> Result<Vec<String>, Error>
+where
+    C: BinaryConfig + for<'a> BinWrite<Args<'a> = ()>,
+    W: Write + Seek,
+{
+    let (mut chords, command_lists) = config.take_chord_table();
+    if chords.len() > MAX_CHORDS {
+        return Err(limit_error(format!(
+            "chord table exceeds {} entries ({} chords)",
+            MAX_CHORDS,
+            chords.len()
+        )));
+    }
+
+    // update offsets in config
+    let command_lists_command_count = chords
+        .iter()
+        .filter(|c| c.command().list_offset().is_some())
+        .count();
+    if command_lists_command_count != command_lists.len() {
+        return Err(Error::Write(format!(
+            "{} chords run a command list but there are {} command lists",
+            command_lists_command_count,
+            command_lists.len()
+        )));
+    }
+
+    let mut next_offset = 0;
+
+    // identical lists share one copy, (list index, offset) of each one written
+    let mut pooled: Vec<(usize, u16)> = vec![];
+    let mut shared = 0;
+    let mut saved = 0;
+
+    let mut j = 0;
+    for (i, chord) in chords.iter_mut().enumerate() {
+        if chord.command().list_offset().is_none() {
+            continue;
+        }
+
+        let size = command_lists[j].0.len() * 4 + 4; // 0u32
+        let existing = pooled
+            .iter()
+            .find(|(k, _)| command_lists[*k] == command_lists[j]);
+        match existing {
+            Some((_, existing_offset)) => {
+                *chord.command_mut() = C::Command::list_at(*existing_offset);
+                shared += 1;
+                saved += size;
+            }
+            None => {
+                // chords are numbered from 1 like everywhere else users see them
+                let Ok(offset) = u16::try_from(next_offset) else {
+                    return Err(limit_error(format!(
+                        "command list offset overflow at chord {}",
+                        i + 1
+                    )));
+                };
+                next_offset += size;
+                if next_offset > MAX_COMMAND_LIST_BYTES {
+                    return Err(limit_error(format!(
+                        "command lists exceed {} bytes at chord {} ({} bytes)",
+                        MAX_COMMAND_LIST_BYTES,
+                        i + 1,
+                        next_offset
+                    )));
+                }
+                *chord.command_mut() = C::Command::list_at(offset);
+                pooled.push((j, offset));
+            }
+        }
+        j += 1;
+    }
+
+    config.set_chord_table(chords, command_lists);
+    let mut output = Cursor::new(vec![]);
+    config.write_le(&mut output).map_err(Error::binary_write)?;
+    if output.get_ref().len() > MAX_FILE_SIZE {
+        return Err(limit_error(format!(
+            "config is {} bytes, over the {} byte limit",
+            output.get_ref().len(),
+            MAX_FILE_SIZE
+        )));
+    }
+    writer.write_all(output.get_ref())?;
+
+    let mut messages = vec![];
+    if shared > 0 {
+        messages.push(format!(
+            "shared {} identical command lists, saving {} bytes",
+            shared, saved
+        ));
+    }
+    Ok(messages)
+}
+
+pub(crate) fn to_layout<C: BinaryConfig>(config: &C) -> Layout {
+    let header = config.header();
+    let settings = Settings {
+        key_repeat: header.key_repeat,
+        repeat_delay: Settings::repeat_delay_from_steps(header.key_repeat_delay as u32),
+        idle_time: Duration::from_secs(header.idle_time as u64),
+        mouse_speed: header.mouse_speed,
+        mouse_accel_table: header.mouse_accel_table,
+        haptic: header.haptic,
+        direct: header.direct,
+        sticky_num: header.sticky_num,
+        sticky_alt: header.sticky_alt,
+        sticky_ctrl: header.sticky_ctrl,
+        sticky_shift: header.sticky_shift,
+        left_mouse_pos: header.left_mouse_pos,
+        ..Default::default()
+    };
+
+    // command lists are stored in chord order
+    let mut command_lists = config.command_lists().iter();
+    let chords = config
+        .chords()
+        .iter()
+        .map(|chord| {
+            let command = chord.command();
+            let action = match command.list_offset() {
+                Some(_) => Action::String(
+                    command_lists
+                        .next()
+                        .map(|list| list.0.iter().map(ListCommand::action).collect())
+                        .unwrap_or_default(),
+                ),
+                None => command.action(),
+            };
+
+            layout::Chord {
+                buttons: chord.button_state(),
+                action,
+            }
+        })
+        .collect();
+
+    Layout { settings, chords }
+}
+
+/// Fill `config` in from a layout, returning a warning for everything it can't store
+pub(crate) fn from_layout<C: BinaryConfig>(
+    mut config: C,
+    layout: &Layout,
+) -> Result<(C, Vec<String>), Error> {
+    let mut warnings = vec![];
+
+    let settings = &layout.settings;
+    if !settings.left_click {
+        warnings.push(format!(
+            "right click mode has no {} equivalent, ignoring it",
+            C::NAME
+        ));
+    }
+    if settings.bluetooth_off {
+        warnings.push(format!(
+            "bluetooth off has no {} equivalent, ignoring it",
+            C::NAME
+        ));
+    }
+
+    config.set_header(&Header {
+        key_repeat: settings.key_repeat,
+        haptic: settings.haptic,
+        direct: settings.direct,
+        sticky_num: settings.sticky_num,
+        sticky_alt: settings.sticky_alt,
+        sticky_ctrl: settings.sticky_ctrl,
+        sticky_shift: settings.sticky_shift,
+        left_mouse_pos: settings.left_mouse_pos,
+        idle_time: settings.idle_time_secs(&mut warnings),
+        mouse_speed: settings.device_mouse_speed(&mut warnings),
+        key_repeat_delay: settings.repeat_delay_steps(&mut warnings),
+        mouse_accel_table: settings.mouse_accel_table,
+    });
+
+    let (mut chords, mut command_lists) = config.take_chord_table();
+    for chord in &layout.chords {
+        if let Action::String(_) = chord.action {
+            // a None command ends a list, and does nothing anyway
+            let commands = chord
+                .action
+                .flatten()
+                .into_iter()
+                .filter(|action| **action != Action::None)
+                .map(C::Command::from_action)
+                .collect::<Result<Vec<_>, _>>()?;
+            command_lists.push(CommandList(commands));
+        }
+
+        chords.push(C::Chord::new(
+            chord.buttons,
+            C::Command::from_action(&chord.action)?,
+        ));
+    }
+    config.set_chord_table(chords, command_lists);
+
+    Ok((config, warnings))
+}
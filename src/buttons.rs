@@ -1,6 +1,6 @@
 #[rustfmt::skip]
 pub enum TwiddlerButtons {
-    T1, T2, T3, T4,
+    T0, T1, T2, T3, T4,
     F0L, F0M, F0R,
     F1L, F1M, F1R,
     F2L, F2M, F2R,
@@ -8,8 +8,9 @@ pub enum TwiddlerButtons {
     F4L, F4M, F4R,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
 pub struct ButtonState {
+    pub t0: bool,
     pub t1: bool,
     pub t2: bool,
     pub t3: bool,
@@ -31,16 +32,193 @@ pub struct ButtonState {
     pub f4r: bool,
 }
 
-pub(crate) fn parse_notation(thumb: String, finger: String) -> ButtonState {
-    if thumb.contains(&['0', '1', '2', '3', '4'][..]) {
+impl std::fmt::Display for ButtonState {
+    /// Buttons like "T1 T4 1R 2M", thumbs with a T in front of their number
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (thumbs, fingers) = to_notation(self);
+        let names = thumbs
+            .split_whitespace()
+            .map(|thumb| format!("T{}", thumb))
+            .chain(fingers.split_whitespace().map(str::to_owned))
+            .collect::<Vec<_>>();
+
+        if names.is_empty() {
+            write!(f, "(none)")
+        } else {
+            write!(f, "{}", names.join(" "))
+        }
+    }
+}
+
+/// How `ButtonState::grid` draws buttons
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+pub enum GridStyle {
+    /// `[X]` for pressed buttons and `[ ]` for the rest
+    #[default]
+    Ascii,
+    /// Filled and empty boxes
+    Unicode,
+}
+
+impl ButtonState {
+    /// The buttons laid out like on the device, the thumb row T0-T4 then finger
+    /// rows 0-4 with their L, M and R columns. Every line is as wide as the first
+    pub fn grid(&self, style: GridStyle) -> Vec<String> {
+        let cell = |pressed: bool| match (style, pressed) {
+            (GridStyle::Ascii, false) => "[ ]",
+            (GridStyle::Ascii, true) => "[X]",
+            (GridStyle::Unicode, false) => " □ ",
+            (GridStyle::Unicode, true) => " ■ ",
+        };
+        let row = |label: &str, buttons: &[bool]| {
+            let cells = buttons.iter().map(|b| cell(*b)).collect::<Vec<_>>();
+            format!("{:<3}{:<19}", label, cells.join(" "))
+        };
+
+        let mut lines = vec![
+            format!("{:<3}{:<19}", "", " T0  T1  T2  T3  T4"),
+            row("T", &[self.t0, self.t1, self.t2, self.t3, self.t4]),
+            format!("{:<3}{:<19}", "", " L   M   R"),
+        ];
+        for (i, (l, m, r)) in [
+            (self.f0l, self.f0m, self.f0r),
+            (self.f1l, self.f1m, self.f1r),
+            (self.f2l, self.f2m, self.f2r),
+            (self.f3l, self.f3m, self.f3r),
+            (self.f4l, self.f4m, self.f4r),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            lines.push(row(&i.to_string(), &[l, m, r]));
+        }
+        lines
+    }
+}
+
+/// Mouse buttons whose click v5 and Dido configs let you change
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseButton {
+    Left,
+    Middle,
+    Right,
+}
+
+impl MouseButton {
+    /// v7 has no separate mouse button settings, so each one becomes a
+    /// chord on the F0 row instead: left on F0L, middle on F0M and right on F0R
+    pub fn button_state(&self) -> ButtonState {
+        ButtonState {
+            f0l: *self == MouseButton::Left,
+            f0m: *self == MouseButton::Middle,
+            f0r: *self == MouseButton::Right,
+            ..Default::default()
+        }
+    }
+
+    pub fn from_button_state(button_state: &ButtonState) -> Option<Self> {
+        [MouseButton::Left, MouseButton::Middle, MouseButton::Right]
+            .into_iter()
+            .find(|button| button.button_state() == *button_state)
+    }
+}
+
+pub fn parse_notation(thumb: String, finger: String) -> ButtonState {
+    // T4 fingers are space separated row and column pairs like "1R 2M",
+    // legacy fingers are one column letter per row like "RM00"
+    let t4_fingers = !finger.trim().is_empty()
+        && finger
+            .split_whitespace()
+            .all(|f| f.len() == 2 && f.starts_with(|c: char| c.is_ascii_digit()));
+
+    if thumb.contains(&['0', '1', '2', '3', '4'][..]) || t4_fingers {
         parse_t4_notation(thumb, finger)
     } else {
         parse_legacy_notation(thumb, finger)
     }
 }
 
+/// Write buttons in T4 notation, thumbs like "1 4" and fingers like "0M 1R 2M"
+pub fn to_notation(button_state: &ButtonState) -> (String, String) {
+    let thumbs = [
+        ('0', button_state.t0),
+        ('1', button_state.t1),
+        ('2', button_state.t2),
+        ('3', button_state.t3),
+        ('4', button_state.t4),
+    ]
+    .iter()
+    .filter(|(_, pressed)| *pressed)
+    .map(|(name, _)| name.to_string())
+    .collect::<Vec<_>>()
+    .join(" ");
+
+    let fingers = [
+        ("0L", button_state.f0l),
+        ("0M", button_state.f0m),
+        ("0R", button_state.f0r),
+        ("1L", button_state.f1l),
+        ("1M", button_state.f1m),
+        ("1R", button_state.f1r),
+        ("2L", button_state.f2l),
+        ("2M", button_state.f2m),
+        ("2R", button_state.f2r),
+        ("3L", button_state.f3l),
+        ("3M", button_state.f3m),
+        ("3R", button_state.f3r),
+        ("4L", button_state.f4l),
+        ("4M", button_state.f4m),
+        ("4R", button_state.f4r),
+    ]
+    .iter()
+    .filter(|(_, pressed)| *pressed)
+    .map(|(name, _)| *name)
+    .collect::<Vec<_>>()
+    .join(" ");
+
+    (thumbs, fingers)
+}
+
+/// Write buttons in legacy notation, thumbs like "N  S" and fingers like "RM00".
+/// Returns None for chords legacy notation can't express, like T0, F0 or two
+/// buttons on one finger
+pub fn to_legacy_notation(button_state: &ButtonState) -> Option<(String, String)> {
+    if button_state.t0 || button_state.f0l || button_state.f0m || button_state.f0r {
+        return None;
+    }
+
+    let thumbs = [
+        ('N', button_state.t1),
+        ('A', button_state.t2),
+        ('C', button_state.t3),
+        ('S', button_state.t4),
+    ]
+    .iter()
+    .map(|(name, pressed)| if *pressed { *name } else { ' ' })
+    .collect::<String>();
+
+    let mut fingers = String::new();
+    for (l, m, r) in [
+        (button_state.f1l, button_state.f1m, button_state.f1r),
+        (button_state.f2l, button_state.f2m, button_state.f2r),
+        (button_state.f3l, button_state.f3m, button_state.f3r),
+        (button_state.f4l, button_state.f4m, button_state.f4r),
+    ] {
+        match (l, m, r) {
+            (false, false, false) => fingers.push('0'),
+            (true, false, false) => fingers.push('L'),
+            (false, true, false) => fingers.push('M'),
+            (false, false, true) => fingers.push('R'),
+            _ => return None,
+        }
+    }
+
+    Some((thumbs, fingers))
+}
+
 fn parse_t4_notation(thumb: String, finger: String) -> ButtonState {
     let mut button_state = ButtonState {
+        t0: false,
         t1: false,
         t2: false,
         t3: false,
@@ -65,6 +243,7 @@ fn parse_t4_notation(thumb: String, finger: String) -> ButtonState {
     // Parse thumb notation
     for button in thumb.chars() {
         match button {
+            '0' => button_state.t0 = true,
             '1' => button_state.t1 = true,
             '2' => button_state.t2 = true,
             '3' => button_state.t3 = true,
@@ -76,10 +255,13 @@ fn parse_t4_notation(thumb: String, finger: String) -> ButtonState {
     // Parse finger notation
     for finger_button in finger.split_whitespace() {
         let mut chars = finger_button.chars();
-        let finger_row = chars.next().unwrap();
-        let finger_col = chars.next().unwrap();
+        let (Some(finger_row), Some(finger_col)) = (chars.next(), chars.next()) else {
+            continue;
+        };
         match (finger_row, finger_col) {
+            ('0', 'L') => button_state.f0l = true,
             ('0', 'M') => button_state.f0m = true,
+            ('0', 'R') => button_state.f0r = true,
             ('1', 'L') => button_state.f1l = true,
             ('1', 'M') => button_state.f1m = true,
             ('1', 'R') => button_state.f1r = true,
@@ -101,6 +283,7 @@ fn parse_t4_notation(thumb: String, finger: String) -> ButtonState {
 
 fn parse_legacy_notation(thumb: String, finger: String) -> ButtonState {
     let mut button_state = ButtonState {
+        t0: false,
         t1: false,
         t2: false,
         t3: false,
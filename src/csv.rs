@@ -1,11 +1,10 @@
-use std::{
-    io::{Read, Seek},
-    vec,
-};
+use std::io::{Read, Seek, Write};
 
 use crate::{
     buttons::{self, ButtonState},
+    error::{Error, Location},
     hid,
+    layout::{self, Action, Layout},
 };
 
 #[derive(Debug, serde::Deserialize)]
@@ -19,96 +18,234 @@ pub struct Chord {
     output: String,
 }
 
-pub fn parse<R: Read + Seek>(reader: &mut R) -> Result<Vec<Chord>, Box<dyn std::error::Error>> {
+pub fn parse<R: Read + Seek>(reader: &mut R) -> Result<Vec<Chord>, Error> {
     let mut rdr = csv::Reader::from_reader(reader);
 
     let result: Result<Vec<Chord>, csv::Error> = rdr.deserialize().collect();
     match result {
         Ok(chords) => Ok(chords),
-        Err(e) => Err(Box::new(e)),
+        Err(e) => {
+            let location = match e.position() {
+                Some(position) => Location::Line(position.line() as usize),
+                None => Location::Unknown,
+            };
+            Err(Error::parse("csv", location, e))
+        }
     }
 }
 
 impl Into<ButtonState> for Chord {
     fn into(self) -> ButtonState {
-        let thumbs = self.thumbs.unwrap();
-        let fingers = self.fingers.unwrap();
+        let thumbs = self.thumbs.unwrap_or_default();
+        let fingers = self.fingers.unwrap_or_default();
         buttons::parse_notation(thumbs, fingers)
     }
 }
 
 impl Chord {
-    pub fn get_hid_pairs(&self) -> Vec<(u8, u8)> {
-        if self.output.len() == 1 {
-            match hid::keys_hid().get_by_right(&self.output) {
-                Some(key) => return vec![(0, *key)],
-                None => return vec![(0, 0)],
-            }
-        }
-
+    /// Turn the Keyboard Output column into (modifier, key code) pairs.
+    ///
+    /// Plain characters are typed as on a US keyboard, `<Name>` types a key
+    /// from `hid::keys_hid()`, `<HIDCode 0x04>` types a raw key code and
+    /// `<L-Ctrl>...</L-Ctrl>` holds a modifier for everything in between.
+    /// A `<` that doesn't start a known tag is typed as is, characters with no
+    /// key are skipped with a warning.
+    pub fn get_hid_pairs(&self, warnings: &mut Vec<String>) -> Vec<(u8, u8)> {
         let mut hid_pairs: Vec<(u8, u8)> = Vec::new();
 
         let mut current_modifiers: u8 = 0;
 
-        let mut reading_tag = false;
-        let mut tag_start = 0;
-        let mut closing = false;
-
-        for (i, c) in self.output.chars().enumerate() {
-            match (c, reading_tag) {
-                ('<', false) => {
-                    reading_tag = true;
-                    tag_start = i;
-                }
-                ('<', true) => {
-                    // Unexpected '<' in tag treat it as the user putting the last '<' as a key
-                    // todo: this needs shift key to type properly
-                    // update tag start to this position too
-                    tag_start = i;
-                    hid_pairs.push((current_modifiers, 0x64));
-                }
-                ('>', true) => {
-                    reading_tag = false;
-                    let tag_contents = &self.output[tag_start + 1..i];
-                    println!("{}", tag_contents);
-
-                    let modifier = match tag_contents {
-                        "L-Ctrl" => 0x01,
-                        "L-Shift" => 0x02,
-                        "L-Alt" => 0x04,
-                        "L-Gui" => 0x08,
-                        "R-Ctrl" => 0x10,
-                        "R-Shift" => 0x20,
-                        "R-Alt" => 0x40,
-                        "R-Gui" => 0x80,
-                        _ => 0,
+        let mut rest = self.output.as_str();
+        while let Some(c) = rest.chars().next() {
+            if c == '<' {
+                if let Some(end) = rest.find('>') {
+                    let tag_contents = &rest[1..end];
+                    let (closing, name) = match tag_contents.strip_prefix('/') {
+                        Some(name) => (true, name),
+                        None => (false, tag_contents),
                     };
 
-                    if closing {
-                        current_modifiers &= !modifier;
-                    } else {
-                        current_modifiers |= modifier;
+                    if let Some(modifier) = parse_modifier(name) {
+                        if closing {
+                            current_modifiers &= !modifier;
+                        } else {
+                            current_modifiers |= modifier;
+                        }
+                        rest = &rest[end + 1..];
+                        continue;
+                    }
+
+                    if !closing {
+                        if let Some(key) = parse_key(name) {
+                            hid_pairs.push((current_modifiers, key));
+                            rest = &rest[end + 1..];
+                            continue;
+                        }
                     }
                 }
-                ('>', false) => {
-                    // Unexpected '>' in tag treat it as the user putting '>' as a key
-                    // todo: this needs shift key to type properly
-                    hid_pairs.push((current_modifiers, 0x64));
+            }
+
+            match hid::ascii_hid().get_by_left(&c) {
+                Some((modifier, key)) => hid_pairs.push((current_modifiers | modifier, *key)),
+                None => warnings.push(format!(
+                    "no key for '{}' in \"{}\", skipping it",
+                    c, self.output
+                )),
+            }
+            rest = &rest[c.len_utf8()..];
+        }
+
+        hid_pairs
+    }
+}
+
+/// Csv files only have chords, the settings are left at their defaults.
+/// Returns a warning for every chord or character that's skipped
+pub fn to_layout(chords: Vec<Chord>) -> (Layout, Vec<String>) {
+    let mut layout = Layout::default();
+    let mut warnings = vec![];
+    for (i, c) in chords.into_iter().enumerate() {
+        let hid_pairs = c.get_hid_pairs(&mut warnings);
+        let buttons: ButtonState = c.into();
+
+        let action = match hid_pairs.as_slice() {
+            [] => {
+                warnings.push(format!(
+                    "skipping chord {} ({}) with no keyboard output",
+                    i + 1,
+                    buttons
+                ));
+                continue;
+            }
+            [(modifier, key_code)] => Action::Key {
+                modifier: *modifier,
+                key_code: *key_code,
+            },
+            _ => Action::String(
+                hid_pairs
+                    .iter()
+                    .map(|(modifier, key_code)| Action::Key {
+                        modifier: *modifier,
+                        key_code: *key_code,
+                    })
+                    .collect(),
+            ),
+        };
+
+        layout.chords.push(layout::Chord { buttons, action });
+    }
+
+    (layout, warnings)
+}
+
+/// Write a layout out in the format Twiddler Tuner reads, returning a warning
+/// for everything CSV can't store
+pub fn write<W: Write>(layout: &Layout, writer: &mut W) -> Result<Vec<String>, Error> {
+    let mut warnings = vec![];
+    // Twiddler Tuner writes a byte order mark, keep it so the file looks the same
+    writer.write_all("\u{feff}".as_bytes())?;
+
+    let mut wtr = csv::WriterBuilder::new()
+        .quote_style(csv::QuoteStyle::Always)
+        .from_writer(writer);
+
+    wtr.write_record(["Thumbs", " Fingers", "Keyboard Output"])?;
+
+    for (i, chord) in layout.chords.iter().enumerate() {
+        let output = match &chord.action {
+            Action::Key { modifier, key_code } => hid_to_output(*modifier, *key_code, true),
+            Action::String(_) => {
+                let mut output = String::new();
+                for action in chord.action.flatten() {
+                    match action {
+                        Action::Key { modifier, key_code } => {
+                            output.push_str(&hid_to_output(*modifier, *key_code, false))
+                        }
+                        action => warnings.push(format!(
+                            "skipping {} in the string of chord {} ({}), CSV can't store it",
+                            action,
+                            i + 1,
+                            chord.buttons
+                        )),
+                    }
                 }
-                ('/', true) => {
-                    closing = false;
+                output
+            }
+            action => {
+                warnings.push(format!(
+                    "skipping chord {} ({}) with {}, CSV can't store it",
+                    i + 1,
+                    chord.buttons,
+                    action
+                ));
+                continue;
+            }
+        };
+
+        let (thumbs, fingers) = buttons::to_notation(&chord.buttons);
+        wtr.write_record([thumbs, fingers, output])?;
+    }
+
+    wtr.flush()?;
+
+    Ok(warnings)
+}
+
+/// Text that `Chord::get_hid_pairs` reads back as the same key press
+fn hid_to_output(modifier: u8, key_code: u8, single: bool) -> String {
+    let mut modifier = modifier;
+
+    // a plain character, typed with shift if needed
+    let mut key = None;
+    if single || !matches!(key_code, 0x28 | 0x2B) {
+        for shift in [modifier & 0x2, 0] {
+            if let Some(c) = hid::ascii_hid().get_by_right(&(shift, key_code)) {
+                if !(single && c.is_whitespace()) {
+                    key = Some(c.to_string());
+                    modifier &= !shift;
+                    break;
                 }
-                ('/', false) => hid_pairs.push((current_modifiers, 0x38)),
-                (_, false) => match hid::keys_hid().get_by_right(&self.output) {
-                    Some(key) => hid_pairs.push((current_modifiers, *key)),
-                    None => {}
-                },
-                (_, true) => {}
             }
         }
+    }
 
-        hid_pairs
+    let key = key.unwrap_or_else(|| match hid::keys_hid().get_by_left(&key_code) {
+        Some(name) => format!("<{}>", name),
+        None => format!("<HIDCode 0x{:02X}>", key_code),
+    });
+
+    let mut modifiers = hid::modifiers_hid()
+        .iter()
+        .filter(|(_, value)| modifier & **value as u8 != 0)
+        .collect::<Vec<_>>();
+    modifiers.sort_by_key(|(_, value)| **value);
+
+    let open = modifiers
+        .iter()
+        .map(|(name, _)| format!("<{}>", name))
+        .collect::<String>();
+    let close = modifiers
+        .iter()
+        .rev()
+        .map(|(name, _)| format!("</{}>", name))
+        .collect::<String>();
+
+    format!("{}{}{}", open, key, close)
+}
+
+fn parse_modifier(name: &str) -> Option<u8> {
+    hid::modifiers_hid()
+        .iter()
+        .find(|(modifier, _)| modifier.eq_ignore_ascii_case(name))
+        .map(|(_, value)| *value as u8)
+}
+
+fn parse_key(name: &str) -> Option<u8> {
+    if let Some(code) = name.strip_prefix("HIDCode 0x") {
+        return u8::from_str_radix(code, 16).ok();
     }
+
+    hid::keys_hid().get_by_right(name).copied()
 }
 
 #[cfg(test)]
@@ -117,14 +254,89 @@ mod tests {
 
     #[test]
     fn it_works() {
-        /*  parse_output("<L-Ctrl></L-Ctrl>");
+        let chord = |output: &str| Chord {
+            thumbs: None,
+            fingers: Some("1R".to_owned()),
+            output: output.to_owned(),
+        };
 
-        parse_output("<L-Ctrl>a</L-Ctrl>");
+        assert!(chord("a").get_hid_pairs(&mut vec![]) == vec![(0, 0x04)]);
+        assert!(chord("Z").get_hid_pairs(&mut vec![]) == vec![(0x02, 0x1D)]);
+        assert!(chord("<").get_hid_pairs(&mut vec![]) == vec![(0x02, 0x36)]);
+        assert!(chord("<Space>").get_hid_pairs(&mut vec![]) == vec![(0, 0x2C)]);
+        assert!(chord("<L-Ctrl></L-Ctrl>").get_hid_pairs(&mut vec![]) == vec![]);
+        assert!(chord("<L-Ctrl>a</L-Ctrl>").get_hid_pairs(&mut vec![]) == vec![(0x01, 0x04)]);
+        assert!(
+            chord("<L-Ctrl><HIDCode 0x04></L-Ctrl>").get_hid_pairs(&mut vec![])
+                == vec![(0x01, 0x04)]
+        );
+        assert!(chord("<L-Ctrl>></L-Ctrl>").get_hid_pairs(&mut vec![]) == vec![(0x03, 0x37)]);
+        assert!(
+            chord("<R-Ctrl>a</R-Ctrl>b").get_hid_pairs(&mut vec![])
+                == vec![(0x10, 0x04), (0, 0x05)]
+        );
+        assert!(chord("I ").get_hid_pairs(&mut vec![]) == vec![(0x02, 0x0C), (0, 0x2C)]);
 
-        parse_output("<L-Ctrl><HIDCode 0x04></L-Ctrl>");
+        let mut warnings = vec![];
+        assert!(chord("aé").get_hid_pairs(&mut warnings) == vec![(0, 0x04)]);
+        assert!(warnings == vec!["no key for 'é' in \"aé\", skipping it"]);
+
+        let button_state: ButtonState = chord("a").into();
+        assert!(button_state.f1r == true);
+        assert!(button_state.t1 == false);
+    }
+
+    #[test]
+    fn test_write() {
+        let mut file = std::fs::File::open("configs/twiddler_cfg_CoolHand.csv").unwrap();
+        let chords = parse(&mut file).unwrap();
+
+        // every output makes it back to the same key presses
+        for chord in &chords {
+            let hid_pairs = chord.get_hid_pairs(&mut vec![]);
+            let single = hid_pairs.len() == 1;
+            let output = hid_pairs
+                .iter()
+                .map(|(modifier, key_code)| hid_to_output(*modifier, *key_code, single))
+                .collect::<String>();
+
+            let written = Chord {
+                thumbs: None,
+                fingers: None,
+                output,
+            };
+            assert!(written.get_hid_pairs(&mut vec![]) == hid_pairs);
+        }
 
-        parse_output("<L-Ctrl>></L-Ctrl>");
+        assert!(hid_to_output(0x02, 0x04, true) == "A");
+        assert!(hid_to_output(0x10, 0x13, true) == "<R-Ctrl>p</R-Ctrl>");
+        assert!(hid_to_output(0x20, 0x20, true) == "<R-Shift>3</R-Shift>");
+        assert!(hid_to_output(0x03, 0x37, true) == "<L-Ctrl>></L-Ctrl>");
+        assert!(hid_to_output(0, 0x2C, true) == "<Space>");
+        assert!(hid_to_output(0, 0x2C, false) == " ");
+        assert!(hid_to_output(0, 0x28, false) == "<Return>");
+        assert!(hid_to_output(0, 0x90, true) == "<HIDCode 0x90>");
 
-        parse_output("<");*/
+        // what CSV can't store is skipped, saying which chord it was in
+        let layout = Layout::new()
+            .with_chord(
+                buttons::parse_notation(String::new(), "1L".to_owned()),
+                Action::Mouse(layout::Mouse::click(buttons::MouseButton::Left)),
+            )
+            .with_chord(
+                buttons::parse_notation("1".to_owned(), "2M".to_owned()),
+                Action::String(vec![
+                    Action::key(0, 0x04),
+                    Action::Delay(std::time::Duration::from_millis(50)),
+                ]),
+            );
+        let warnings = write(&layout, &mut vec![]).unwrap();
+        assert!(
+            warnings
+                == vec![
+                    "skipping chord 1 (1L) with Mouse left click, CSV can't store it",
+                    "skipping Delay 50ms in the string of chord 2 (T1 2M), CSV can't store it",
+                ]
+        );
     }
 }
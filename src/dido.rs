@@ -1,22 +1,54 @@
-use std::io::{BufRead, Read, Seek};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    io::{BufRead, Read, Write},
+    iter::Peekable,
+};
 
 use nom::{
     branch::alt,
-    bytes::streaming::{is_not, tag, take},
-    character::{
-        complete::{alphanumeric0, digit0},
-        streaming::{alpha0, digit1, space0, space1},
-    },
+    bytes::complete::{is_not, tag, take},
+    character::complete::{alphanumeric0, digit1, space0, space1},
     combinator::{map, opt},
-    error::VerboseError,
-    sequence::{delimited, pair, preceded, separated_pair, tuple},
+    error::{context, VerboseError, VerboseErrorKind},
+    sequence::{delimited, pair, preceded, tuple},
+    IResult, Offset,
 };
 
-use crate::buttons::{self, ButtonState};
+use crate::{
+    buttons::{self, ButtonState, MouseButton},
+    error::Error,
+    hid,
+    layout::{self, clamp_setting, Action, Layout},
+};
 
 pub struct Config {
+    pub options: Options,
+    pub settings: Settings,
+    /// Mouse buttons from the header, None when they click as normal
+    pub mouse: Vec<(MouseButton, Option<Chord>)>,
     pub chords: Vec<Chord>,
-    pub strings: Vec<Vec<(u8, u8)>>,
+    /// The keys of each string by its `String[n]` index, as (key code, modifier)
+    pub strings: BTreeMap<u32, Vec<(u8, u8)>>,
+}
+
+/// The true/false options at the top of the file, None when left out
+#[derive(Debug, Default)]
+pub struct Options {
+    pub sticky_num: Option<bool>,
+    pub sticky_shift: Option<bool>,
+    pub click_mode: Option<bool>, // true for left click
+    pub key_repeat: Option<bool>,
+    pub direct_key: Option<bool>,
+    pub bluetooth_off: Option<bool>,
+}
+
+/// The numeric settings after the options, None when left out
+#[derive(Debug, Default)]
+pub struct Settings {
+    pub sleep: Option<u32>,        // seconds
+    pub mouse_speed: Option<u32>,  // v5 acceleration factor, see format.md
+    pub repeat_delay: Option<u32>, // 10ms steps
 }
 
 #[derive(Debug)] // Add the Debug trait to the Chord struct
@@ -37,17 +69,151 @@ enum ParseState {
     Done,
 }
 
-pub(crate) fn parse<R: Read + Seek>(reader: &mut R) -> Result<Config, Box<dyn std::error::Error>> {
+/// Whether problems in a Dido file stop the parse
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Mode {
+    /// Fail with every problem found in the file
+    Strict,
+    /// Warn about each problem and skip the line it's on
+    #[default]
+    Lenient,
+}
+
+/// A problem on one line of a Dido file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub file: String,
+    /// Starting at 1
+    pub line: usize,
+    /// Starting at 1, counted in characters
+    pub column: usize,
+    /// The text that couldn't be parsed, empty at the end of a line
+    pub text: String,
+    /// What the parser was looking for instead
+    pub expected: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.file.is_empty() {
+            write!(f, "{}:", self.file)?;
+        }
+        write!(
+            f,
+            "{}:{}: expected {}, ",
+            self.line, self.column, self.expected
+        )?;
+        match self.text.as_str() {
+            "" => write!(f, "found nothing"),
+            text => write!(f, "found \"{}\"", text),
+        }
+    }
+}
+
+/// A problem found by one of the line parsers, before we know which line it's on
+#[derive(Debug)]
+struct Problem {
+    column: usize,
+    text: String,
+    expected: String,
+}
+
+impl Problem {
+    /// A problem at `at`, which has to be a slice of `line`
+    fn new(line: &str, at: &str, expected: impl ToString) -> Self {
+        let at = at.trim_start();
+        Problem {
+            column: line[..line.offset(at)].chars().count() + 1,
+            text: at.split_whitespace().next().unwrap_or_default().to_owned(),
+            expected: expected.to_string(),
+        }
+    }
+
+    /// Use the innermost context for what was expected, nom's own name for the
+    /// parser when there isn't one
+    fn from_nom(line: &str, error: nom::Err<VerboseError<&str>>) -> Self {
+        let errors = match error {
+            nom::Err::Error(e) | nom::Err::Failure(e) => e.errors,
+            nom::Err::Incomplete(_) => vec![],
+        };
+        let at = errors
+            .first()
+            .map(|(at, _)| *at)
+            .unwrap_or(&line[line.len()..]);
+        let expected = errors
+            .iter()
+            .find_map(|(_, kind)| match kind {
+                VerboseErrorKind::Context(context) => Some(context.to_string()),
+                _ => None,
+            })
+            .or_else(|| {
+                errors.first().map(|(_, kind)| match kind {
+                    VerboseErrorKind::Char(c) => format!("'{}'", c),
+                    kind => format!("{:?}", kind),
+                })
+            })
+            .unwrap_or_else(|| "more text".to_owned());
+
+        Problem::new(line, at, expected)
+    }
+
+    fn at(self, file: &str, line: usize) -> Diagnostic {
+        Diagnostic {
+            file: file.to_owned(),
+            line,
+            column: self.column,
+            text: self.text,
+            expected: self.expected,
+        }
+    }
+}
+
+/// Read a Dido file, `file` is only used to say where problems are. In lenient
+/// mode the diagnostics for the lines that were skipped come back with the config
+pub fn parse<R: Read>(
+    reader: &mut R,
+    file: &str,
+    mode: Mode,
+) -> Result<(Config, Vec<Diagnostic>), Error> {
+    let (config, diagnostics) = parse_with_diagnostics(reader, file)?;
+    match mode {
+        Mode::Strict if !diagnostics.is_empty() => Err(Error::Diagnostics(diagnostics)),
+        _ => Ok((config, diagnostics)),
+    }
+}
+
+/// Read as much of a Dido file as we can, lines with problems are skipped and
+/// a diagnostic for each one is returned with the config
+pub fn parse_with_diagnostics<R: Read>(
+    reader: &mut R,
+    file: &str,
+) -> Result<(Config, Vec<Diagnostic>), Error> {
     let mut parse_state = ParseState::Options;
 
     let buffered = std::io::BufReader::new(reader);
 
-    let mut lines = buffered.lines();
+    let mut lines = buffered.lines().enumerate().peekable();
+    // the next line, unless it ends a section
+    let next_line = |lines: &mut Peekable<_>| {
+        lines.next_if(|(_, line): &(usize, std::io::Result<String>)| {
+            !matches!(line, Ok(line) if line.contains("---"))
+        })
+    };
 
+    let mut options = Options::default();
+    let mut settings = Settings::default();
+    let mut mouse = vec![];
     let mut chords = vec![];
-    let mut strings = vec![];
+    let mut strings = BTreeMap::new();
+    let mut diagnostics = vec![];
+
+    // skip string keys after a String[n] line we couldn't read
+    let mut skipping_keys = false;
+
+    while let Some((line_index, line)) = lines.next() {
+        let line = line?;
+        let line_number = line_index + 1;
 
-    while let Some(Ok(line)) = lines.next() {
         if line.contains("---") {
             match line.as_str() {
                 "# --- end of options" => parse_state = ParseState::Settings,
@@ -61,95 +227,155 @@ pub(crate) fn parse<R: Read + Seek>(reader: &mut R) -> Result<Config, Box<dyn st
             continue;
         }
 
-        // Ignore comments unless we're parsing the string section
-        if line.starts_with("#") && parse_state != ParseState::Strings {
+        // Ignore comments and blank lines unless we're parsing the string section
+        if (line.starts_with("#") || line.trim().is_empty()) && parse_state != ParseState::Strings {
             continue;
         }
 
         match parse_state {
             ParseState::Options => {
-                // todo: parse options
-                //parse_key_value(line);
+                let result = parse_key_value(&line).and_then(|(key, value)| {
+                    let value = match value {
+                        "true" => true,
+                        "false" => false,
+                        _ => return Err(Problem::new(&line, value, "true or false")),
+                    };
+
+                    match key {
+                        "sticky_num" => options.sticky_num = Some(value),
+                        "sticky_shift" => options.sticky_shift = Some(value),
+                        "click_mode" => options.click_mode = Some(value),
+                        "key_repeat" => options.key_repeat = Some(value),
+                        "direct_key" => options.direct_key = Some(value),
+                        "bluetooth_off" => options.bluetooth_off = Some(value),
+                        _ => return Err(Problem::new(&line, key, "an option name")),
+                    }
+                    Ok(())
+                });
+                if let Err(problem) = result {
+                    diagnostics.push(problem.at(file, line_number));
+                }
             }
             ParseState::Settings => {
-                // todo: parse settings
+                let result = parse_key_value(&line).and_then(|(key, value)| {
+                    let Ok(value) = value.parse::<u32>() else {
+                        return Err(Problem::new(&line, value, "a number"));
+                    };
+
+                    match key {
+                        "sleep" => settings.sleep = Some(value),
+                        "mouse_speed" => settings.mouse_speed = Some(value),
+                        "repeat_delay" => settings.repeat_delay = Some(value),
+                        _ => return Err(Problem::new(&line, key, "a setting name")),
+                    }
+                    Ok(())
+                });
+                if let Err(problem) = result {
+                    diagnostics.push(problem.at(file, line_number));
+                }
             }
             ParseState::Header => {
-                let res = parse_key_value(line);
-                match res {
-                    Ok((key, value)) => match key.as_str() {
-                        "mouse_left" => {
-                            println!("mouse_left: {}", value);
-                            if value == "false" {
-                                lines.next();
-                            }
+                let result = parse_key_value(&line).and_then(|(key, value)| {
+                    let button = match key {
+                        "mouse_left" => MouseButton::Left,
+                        "mouse_right" => MouseButton::Right,
+                        "mouse_mid" => MouseButton::Middle,
+                        _ => {
+                            return Err(Problem::new(
+                                &line,
+                                key,
+                                "mouse_left, mouse_right or mouse_mid",
+                            ))
                         }
-                        "mouse_right" => {
-                            println!("mouse_right: {}", value);
-                            if value == "false" {
-                                lines.next();
-                            }
-                        }
-                        "mouse_mid" => {
-                            println!("mouse_mid: {}", value);
-                            if value == "false" {
-                                lines.next();
-                            }
-                        }
-                        _ => {}
-                    },
-                    Err(e) => {
-                        println!("error: {:?}", e);
+                    };
+
+                    match value {
+                        "true" => Ok((button, false)),
+                        "false" => Ok((button, true)),
+                        _ => Err(Problem::new(&line, value, "true or false")),
                     }
+                });
+
+                match result {
+                    Ok((button, false)) => mouse.push((button, None)),
+                    // false means the next line has the chord output to use instead of a click
+                    Ok((button, true)) => match next_line(&mut lines) {
+                        Some((index, output)) => match parse_chord_line(&output?) {
+                            Ok(chord) => mouse.push((button, Some(chord))),
+                            Err(problem) => diagnostics.push(problem.at(file, index + 1)),
+                        },
+                        None => diagnostics.push(
+                            Problem::new(
+                                &line,
+                                &line[line.len()..],
+                                "a chord line on the next line with what the button does",
+                            )
+                            .at(file, line_number),
+                        ),
+                    },
+                    Err(problem) => diagnostics.push(problem.at(file, line_number)),
                 }
             }
-            ParseState::Chords => {
-                if let Ok(chord) = parse_chord_line(line) {
-                    chords.push(chord);
-                }
-            }
+            ParseState::Chords => match parse_chord_line(&line) {
+                Ok(chord) => chords.push(chord),
+                Err(problem) => diagnostics.push(problem.at(file, line_number)),
+            },
             ParseState::Strings => {
-                let res = parse_string_index(&line);
-                match res {
-                    Ok((index, len)) => {
-                        let mut hids = vec![];
-
-                        for i in 0..len {
-                            let line = lines.next().unwrap().unwrap();
-
-                            let mut string_line_parser = pair(
-                                digit1,
-                                opt(preceded(
-                                    nom::character::streaming::char('+'),
-                                    alphanumeric0,
-                                )),
-                            );
-
-                            assert!(matches!(
-                                string_line_parser("asl;kfjsl;kfdj"),
-                                Err(nom::Err::Error(VerboseError { .. }))
-                            ));
-
-                            let res = string_line_parser(&line);
-                            match res {
-                                Ok((_, (hid, out_mods))) => {
-                                    let hid_u8 = hid.parse::<u8>().unwrap();
-                                    let mod_u8 = parse_mod_out(out_mods.unwrap_or_default());
-
-                                    hids.push((hid_u8, mod_u8));
-                                }
-                                Err(e) => {
-                                    println!("error: {:?}", e);
-                                }
-                            }
-                        }
+                if line.starts_with("#") && !line.contains("String[") {
+                    continue;
+                }
+                if !line.starts_with("#") && skipping_keys {
+                    continue;
+                }
+                skipping_keys = false;
 
-                        strings.push(hids);
+                // strings are looked up by their index, so a skipped one doesn't move the rest
+                let (index, len) = match parse_string_index(&line) {
+                    Ok((index, _)) if strings.contains_key(&index) => {
+                        let at = &line[line.find("String[").unwrap_or_default()..];
+                        diagnostics.push(
+                            Problem::new(&line, at, "a String index that isn't used yet")
+                                .at(file, line_number),
+                        );
+                        skipping_keys = true;
+                        continue;
+                    }
+                    Ok(res) => res,
+                    Err(problem) => {
+                        diagnostics.push(problem.at(file, line_number));
+                        skipping_keys = true;
+                        continue;
                     }
-                    Err(e) => {
-                        println!("error: {:?}", e);
+                };
+
+                let mut hids = vec![];
+                for _ in 0..len {
+                    // a string with fewer keys than characters runs into the next one
+                    let key_line = next_line(&mut lines).filter(|(_, key_line)| {
+                        !matches!(key_line, Ok(key_line) if key_line.starts_with("#"))
+                    });
+                    let Some((key_index, key_line)) = key_line else {
+                        diagnostics.push(
+                            Problem::new(
+                                &line,
+                                &line[line.len()..],
+                                match len {
+                                    1 => format!("1 key line after String[{}]", index),
+                                    _ => format!("{} key lines after String[{}]", len, index),
+                                },
+                            )
+                            .at(file, line_number),
+                        );
+                        break;
+                    };
+
+                    match parse_string_key(&key_line?) {
+                        Ok(key) => hids.push(key),
+                        Err(problem) => diagnostics.push(problem.at(file, key_index + 1)),
                     }
                 }
+
+                strings.insert(index, hids);
             }
             ParseState::Done => {
                 // done
@@ -157,34 +383,398 @@ pub(crate) fn parse<R: Read + Seek>(reader: &mut R) -> Result<Config, Box<dyn st
         }
     }
 
-    Ok(Config { chords, strings })
+    Ok((
+        Config {
+            options,
+            settings,
+            mouse,
+            chords,
+            strings,
+        },
+        diagnostics,
+    ))
 }
 
-fn parse_mod_out(out: &str) -> u8 {
-    out.chars()
-        .zip(out.chars().skip(1))
-        .fold(0, |acc, (a, b)| match (a, b) {
-            ('L', 'C') => acc | 0x1,
-            ('L', 'S') => acc | 0x2,
-            ('L', 'A') => acc | 0x4,
-            ('L', 'G') => acc | 0x8,
-            ('R', 'C') => acc | 0x10,
-            ('R', 'S') => acc | 0x20,
-            ('R', 'A') => acc | 0x40,
-            ('R', 'G') => acc | 0x80,
-            _ => acc,
-        })
+/// Parse modifiers like "RCLS" from `out`, a slice of `line`
+fn parse_modifiers(line: &str, out: &str) -> Result<u8, Problem> {
+    let mut modifiers = 0;
+    for (i, pair) in out.as_bytes().chunks(2).enumerate() {
+        match MODIFIER_NAMES
+            .iter()
+            .find(|(name, _)| name.as_bytes() == pair)
+        {
+            Some((_, value)) => modifiers |= value,
+            None => {
+                return Err(Problem::new(
+                    line,
+                    &out[i * 2..],
+                    "modifiers LC, LS, LA, LG, RC, RS, RA or RG",
+                ))
+            }
+        }
+    }
+    Ok(modifiers)
+}
+
+const MODIFIER_NAMES: [(&str, u8); 8] = [
+    ("LC", 0x1),
+    ("LS", 0x2),
+    ("LA", 0x4),
+    ("LG", 0x8),
+    ("RC", 0x10),
+    ("RS", 0x20),
+    ("RA", 0x40),
+    ("RG", 0x80),
+];
+
+fn mod_out(modifier: u8) -> String {
+    MODIFIER_NAMES
+        .iter()
+        .filter(|(_, value)| modifier & value != 0)
+        .map(|(name, _)| *name)
+        .collect()
+}
+
+/// HHH+LCLS key field, padded so the comments line up
+fn key_field(modifier: u8, key_code: u8) -> String {
+    let key = match modifier {
+        0 => format!("{:03}", key_code),
+        _ => format!("{:03}+{}", key_code, mod_out(modifier)),
+    };
+    format!("{:<20}", key)
+}
+
+fn key_comment(key_code: u8) -> String {
+    let ascii = hid::ascii_hid();
+    match (
+        ascii.get_by_right(&(0, key_code)),
+        ascii.get_by_right(&(0x2, key_code)),
+    ) {
+        (Some(' '), _) => "Keyboard Spacebar".to_owned(),
+        (Some(c), Some(shifted)) => format!("Keyboard {} and {}", c, shifted),
+        _ => match hid::keys_hid().get_by_left(&key_code) {
+            Some(name) => format!("Keyboard {}", name),
+            None => format!("Keyboard 0x{:02X}", key_code),
+        },
+    }
 }
 
-fn parse_key_value(line: String) -> Result<(String, String), Box<dyn std::error::Error>> {
-    let mut items = line.split("=");
-    if let Some(key) = items.next() {
-        if let Some(value) = items.next() {
-            return Ok((key.trim().to_owned(), value.trim().to_owned()));
+/// One character per key for the `# String[n]="..."` line, the parser
+/// counts these to know how many key lines follow
+fn string_char(modifier: u8, key_code: u8) -> char {
+    let ascii = hid::ascii_hid();
+    let shift = if modifier & 0x22 != 0 { 0x2 } else { 0 };
+    match ascii.get_by_right(&(shift, key_code)) {
+        Some(c) if c.is_ascii_graphic() && *c != '"' || *c == ' ' => *c,
+        _ => '?',
+    }
+}
+
+/// The part of a chord line after the buttons, None for actions Dido can't store.
+/// The keys of strings are added to `strings` so they can be written at the end.
+fn chord_output(
+    action: &Action,
+    strings: &mut Vec<Vec<(u8, u8)>>,
+    warnings: &mut Vec<String>,
+) -> Option<String> {
+    match action {
+        Action::Key { modifier, key_code } => Some(format!(
+            "{}:# {}",
+            key_field(*modifier, *key_code),
+            key_comment(*key_code)
+        )),
+        Action::String(_) => {
+            let keys = action
+                .flatten()
+                .into_iter()
+                .filter_map(|action| match action {
+                    Action::Key { modifier, key_code } => Some((*modifier, *key_code)),
+                    action => {
+                        warnings.push(format!(
+                            "skipping {} in String[{}], Dido can't store it",
+                            action,
+                            strings.len()
+                        ));
+                        None
+                    }
+                })
+                .collect::<Vec<_>>();
+            // an empty String[n]="" line can't be read back
+            if keys.is_empty() {
+                return None;
+            }
+
+            strings.push(keys);
+            Some(format!("String[{}]:", strings.len() - 1))
         }
+        _ => None,
     }
+}
+
+/// Write a layout out in the Dido text format, returning a warning for every
+/// chord that's left out
+pub fn write<W: Write>(layout: &Layout, writer: &mut W) -> Result<Vec<String>, Error> {
+    let mut warnings = vec![];
+    let option = |name: &str, value: bool, comment: &str| {
+        format!("{:<25}# {}\n", format!("{}={}", name, value), comment)
+    };
+    let setting = |name: &str, value: u64, comment: &str| {
+        format!("{:<25}# {}\n", format!("{}={}", name, value), comment)
+    };
 
-    bail!("Invalid key value pair: {}", line);
+    let mut out = String::new();
+    out.push_str(&format!(
+        "# written by twiddler_cfg v{}\n",
+        env!("CARGO_PKG_VERSION")
+    ));
+    out.push_str("#\n# (please edit carefully)\n#\n");
+
+    let settings = &layout.settings;
+    out.push_str(&option(
+        "sticky_num",
+        settings.sticky_num,
+        "Enable Stick Num Key, false=Off, true=On",
+    ));
+    out.push_str(&option(
+        "sticky_shift",
+        settings.sticky_shift,
+        "Enable Stick Shift Key, false=Off, true=On",
+    ));
+    out.push_str(&option(
+        "click_mode",
+        settings.left_click,
+        "Joystick Mouse Click, false=Right-Click, true=Left-Click",
+    ));
+    out.push_str(&option(
+        "key_repeat",
+        settings.key_repeat,
+        "Enable Key Repeat, false=Off, true=On",
+    ));
+    out.push_str(&option(
+        "direct_key",
+        settings.direct,
+        "Enable Direct Key Mode, false=Off, true=On",
+    ));
+    out.push_str(&option(
+        "bluetooth_off",
+        settings.bluetooth_off,
+        "Disable Bluetooth Radio, false=Enabled, true=Disabled",
+    ));
+    out.push_str("# --- end of options\n");
+
+    out.push_str(&setting(
+        "sleep",
+        settings.idle_time.as_secs(),
+        "Sleep Timeout (seconds)",
+    ));
+    out.push_str(&setting(
+        "mouse_speed",
+        settings.mouse_speed as u64,
+        "Mouse Acceleration Factor",
+    ));
+    out.push_str(&setting(
+        "repeat_delay",
+        settings.repeat_delay_steps(&mut warnings) as u64,
+        "Key Repeat Delay (default 100)",
+    ));
+    out.push_str("# --- end of settings\n");
+
+    let mut strings = vec![];
+
+    // chords on a single F0 button are what the mouse buttons do
+    for (name, button) in [
+        ("mouse_mid", MouseButton::Middle),
+        ("mouse_right", MouseButton::Right),
+        ("mouse_left", MouseButton::Left),
+    ] {
+        let action = layout
+            .chords
+            .iter()
+            .enumerate()
+            .find(|(_, chord)| MouseButton::from_button_state(&chord.buttons) == Some(button));
+
+        match action {
+            Some((_, chord)) if chord.action == Action::Mouse(layout::Mouse::click(button)) => {
+                out.push_str(&format!("{:<25}\n", format!("{}=true", name)));
+            }
+            Some((i, chord)) => match chord_output(&chord.action, &mut strings, &mut warnings) {
+                Some(output) => {
+                    out.push_str(&format!("{:<25}\n", format!("{}=false", name)));
+                    out.push_str(&format!("     0000:{}\n", output));
+                }
+                None => {
+                    warnings.push(format!(
+                        "skipping chord {} with {}, Dido can't store it",
+                        i + 1,
+                        chord.action
+                    ));
+                    out.push_str(&format!("{:<25}\n", format!("{}=true", name)));
+                }
+            },
+            None => out.push_str(&format!("{:<25}\n", format!("{}=true", name))),
+        }
+    }
+    out.push_str("# --- end of header\n");
+
+    out.push_str(
+        r#"#                 alt  control
+#                    ||
+#               num |||| shift
+#                   ▼▼▼▼
+# Chord Format:     NACS XXXX:HHH+LCLSLALGRCRSRARG:# comment
+#                  [  chord  ] ▲ [   modifier     ]
+#                              |              LC = Left Ctrl
+#                        keycode in dec       RG = Right GUI
+#
+"#,
+    );
+
+    for (i, chord) in layout.chords.iter().enumerate() {
+        if MouseButton::from_button_state(&chord.buttons).is_some() {
+            // already written in the header
+            continue;
+        }
+        let Some((thumbs, fingers)) = buttons::to_legacy_notation(&chord.buttons) else {
+            warnings.push(format!(
+                "skipping chord {}, Dido can't express its buttons",
+                i + 1
+            ));
+            continue;
+        };
+
+        match chord_output(&chord.action, &mut strings, &mut warnings) {
+            Some(output) => out.push_str(&format!("{} {}:{}\n", thumbs, fingers, output)),
+            None => warnings.push(format!(
+                "skipping chord {} with {}, Dido can't store it",
+                i + 1,
+                chord.action
+            )),
+        }
+    }
+    out.push_str("# --- end of chords\n");
+
+    for (i, keys) in strings.iter().enumerate() {
+        let text = keys
+            .iter()
+            .map(|(modifier, key_code)| string_char(*modifier, *key_code))
+            .collect::<String>();
+        out.push_str(&format!("# String[{}]=\"{}\"\n", i, text));
+
+        for &(modifier, key_code) in keys {
+            out.push_str(&format!(
+                "{}# {}\n",
+                key_field(modifier, key_code),
+                key_comment(key_code)
+            ));
+        }
+    }
+    out.push_str("# --- end of config\n");
+
+    writer.write_all(out.as_bytes())?;
+    Ok(warnings)
+}
+
+/// Turn a Dido config into a layout, settings that are left out keep their defaults
+/// and ones the device can't store are clamped with a warning
+pub fn to_layout(config: &Config) -> Result<(Layout, Vec<String>), Error> {
+    let mut layout = Layout::default();
+    let mut warnings = vec![];
+
+    let options = &config.options;
+    let settings = &mut layout.settings;
+    settings.sticky_num = options.sticky_num.unwrap_or(settings.sticky_num);
+    settings.sticky_shift = options.sticky_shift.unwrap_or(settings.sticky_shift);
+    settings.left_click = options.click_mode.unwrap_or(settings.left_click);
+    settings.key_repeat = options.key_repeat.unwrap_or(settings.key_repeat);
+    settings.direct = options.direct_key.unwrap_or(settings.direct);
+    settings.bluetooth_off = options.bluetooth_off.unwrap_or(settings.bluetooth_off);
+
+    if let Some(sleep) = config.settings.sleep {
+        settings.idle_time = std::time::Duration::from_secs(sleep as u64);
+    }
+    if let Some(mouse_speed) = config.settings.mouse_speed {
+        settings.mouse_speed =
+            clamp_setting("mouse_speed", mouse_speed, 1, 254, &mut warnings) as u8;
+    }
+    if let Some(repeat_delay) = config.settings.repeat_delay {
+        settings.repeat_delay = layout::Settings::repeat_delay_from_steps(clamp_setting(
+            "repeat_delay",
+            repeat_delay,
+            1,
+            250,
+            &mut warnings,
+        ));
+    }
+
+    for c in &config.chords {
+        layout.chords.push(layout::Chord {
+            buttons: c.buttons,
+            action: chord_action(config, c)?,
+        });
+    }
+
+    for (button, action) in &config.mouse {
+        let action = match action {
+            Some(c) => chord_action(config, c)?,
+            None => Action::Mouse(layout::Mouse::click(*button)),
+        };
+
+        layout.chords.push(layout::Chord {
+            buttons: button.button_state(),
+            action,
+        });
+    }
+
+    Ok((layout, warnings))
+}
+
+/// What a chord line types, looking up its string when it has one
+fn chord_action(config: &Config, c: &Chord) -> Result<Action, Error> {
+    match &c.output {
+        ChordOutput::HidCode(key_code) => {
+            let Ok(key_code) = key_code.parse() else {
+                return Err(Error::Conversion(format!(
+                    "Invalid key code {:?} in Dido chord {}",
+                    key_code, c.comment
+                )));
+            };
+            Ok(Action::Key {
+                modifier: c.modifiers,
+                key_code,
+            })
+        }
+        ChordOutput::StringIndex(index) => {
+            let Some(keys) = index
+                .parse::<u32>()
+                .ok()
+                .and_then(|index| config.strings.get(&index))
+            else {
+                return Err(Error::Conversion(format!(
+                    "Dido chord {} uses String[{}] which isn't defined",
+                    c.comment, index
+                )));
+            };
+
+            Ok(Action::String(
+                keys.iter()
+                    .map(|(key_code, modifier)| Action::Key {
+                        modifier: *modifier,
+                        key_code: *key_code,
+                    })
+                    .collect(),
+            ))
+        }
+    }
+}
+
+fn parse_key_value(line: &str) -> Result<(&str, &str), Problem> {
+    // drop the trailing comment
+    let pair = line.split('#').next().unwrap_or_default();
+
+    match pair.split_once('=') {
+        Some((key, value)) => Ok((key.trim(), value.trim())),
+        None => Err(Problem::new(line, pair, "key=value")),
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -201,88 +791,100 @@ fn hid_code(i: &str) -> ChordOutput {
     ChordOutput::HidCode(i.to_string())
 }
 
-pub fn parse_chord_line(line: String) -> Result<Chord, Box<dyn std::error::Error>> {
+/// A key code from `key_code`, a slice of `line`
+fn parse_key_code(line: &str, key_code: &str) -> Result<u8, Problem> {
+    key_code
+        .parse::<u8>()
+        .map_err(|_| Problem::new(line, key_code, "a key code from 0 to 255"))
+}
+
+fn parse_chord_line(line: &str) -> Result<Chord, Problem> {
     // NACS XXXX:HHH+LCLSLALGRCRSRARG:# comment
 
-    let mut parser = nom::sequence::tuple((
-        map(
-            tuple((take(4u8), space1, take(4u8))),
-            |(thumb, _, finger): (&str, _, &str)| {
-                buttons::parse_notation(thumb.to_string(), finger.to_string())
-            },
+    let mut parser = tuple((
+        context("4 thumb buttons like \"N  S\"", take(4u8)),
+        space1,
+        context("4 finger buttons like \"RM00\"", take(4u8)),
+        context("':' after the buttons", nom::character::complete::char(':')),
+        context(
+            "a key code or String[n]",
+            alt((
+                map(
+                    delimited(tag("String["), digit1, nom::character::complete::char(']')),
+                    |index| (index, true),
+                ),
+                map(digit1, |key_code| (key_code, false)),
+            )),
         ),
-        nom::character::streaming::char(':'),
-        alt((
-            delimited(
-                tag("String["),
-                map(digit0, string_index),
-                nom::character::streaming::char(']'),
-            ),
-            map(digit0, hid_code),
-        )),
-        opt(preceded(
-            nom::character::streaming::char('+'),
-            alphanumeric0,
-        )),
+        opt(preceded(nom::character::complete::char('+'), alphanumeric0)),
         space0,
-        nom::character::streaming::char(':'),
+        context(
+            "':' before the comment",
+            nom::character::complete::char(':'),
+        ),
     ));
 
-    assert!(matches!(
-        parser("1.2alksdfjlksaflkasfj"),
-        Err(nom::Err::Error(VerboseError { .. }))
-    ));
+    let result: IResult<_, _, VerboseError<&str>> = parser(line);
+    let (comment, (thumb, _, finger, _, output, out_mods, _, _)) =
+        result.map_err(|e| Problem::from_nom(line, e))?;
+
+    let output = match output {
+        (index, true) => string_index(index),
+        (key_code, false) => {
+            parse_key_code(line, key_code)?;
+            hid_code(key_code)
+        }
+    };
 
-    let result = parser(&line);
+    Ok(Chord {
+        buttons: buttons::parse_notation(thumb.to_string(), finger.to_string()),
+        output,
+        modifiers: parse_modifiers(line, out_mods.unwrap_or_default())?,
+        comment: comment.to_string(),
+    })
+}
 
-    match result {
-        Ok((comment, (button_state, _, output, out_mods, _, _))) => {
-            let mod_u8 = parse_mod_out(out_mods.unwrap_or_default());
+/// The `HHH+LCLS` key lines after each string, as (key code, modifier)
+fn parse_string_key(line: &str) -> Result<(u8, u8), Problem> {
+    let mut parser = pair(
+        context("a key code", digit1),
+        opt(preceded(nom::character::complete::char('+'), alphanumeric0)),
+    );
 
-            return Ok(Chord {
-                buttons: button_state,
-                output,
-                modifiers: mod_u8,
-                comment: comment.to_string(),
-            });
-        }
-        Err(e) => {
-            println!("error: {:?}", e);
-        }
-    }
-    bail!("Invalid chord line: {}", line)
+    let result: IResult<_, _, VerboseError<&str>> = parser(line);
+    let (_, (key_code, out_mods)) = result.map_err(|e| Problem::from_nom(line, e))?;
+
+    Ok((
+        parse_key_code(line, key_code)?,
+        parse_modifiers(line, out_mods.unwrap_or_default())?,
+    ))
 }
 
-fn parse_string_index(input: &str) -> Result<(u32, usize), Box<dyn std::error::Error>> {
+fn parse_string_index(line: &str) -> Result<(u32, usize), Problem> {
     // # String[5]="you "
     // # String[60]="650-489-5484"
 
-    let mut parser = nom::sequence::tuple((
-        nom::character::streaming::char('#'),
+    let mut parser = tuple((
+        nom::character::complete::char('#'),
         space0,
-        delimited(tag("String["), digit0, nom::character::streaming::char(']')),
-        nom::character::streaming::char('='),
-        delimited(tag("\""), is_not("\""), tag("\"")),
+        context(
+            "String[n]",
+            delimited(tag("String["), digit1, nom::character::complete::char(']')),
+        ),
+        context("'='", nom::character::complete::char('=')),
+        context(
+            "the string's text in quotes",
+            delimited(tag("\""), is_not("\""), tag("\"")),
+        ),
     ));
 
-    assert!(matches!(
-        parser("1.2"),
-        Err(nom::Err::Error(VerboseError { .. }))
-    ));
+    let result: IResult<_, _, VerboseError<&str>> = parser(line);
+    let (_, (_, _, index, _, characters)) = result.map_err(|e| Problem::from_nom(line, e))?;
 
-    let res = parser(input);
-    match res {
-        Ok((_, (_, _, index, _, characters))) => {
-            if let Ok(index) = index.parse::<u32>() {
-                return Ok((index, characters.len()));
-            }
-        }
-        Err(e) => {
-            println!("error: {:?}", e);
-        }
+    match index.parse::<u32>() {
+        Ok(index) => Ok((index, characters.chars().count())),
+        Err(_) => Err(Problem::new(line, index, "a string index")),
     }
-
-    bail!("Invalid string index: {}", input);
 }
 
 #[cfg(test)]
@@ -291,21 +893,207 @@ mod tests {
 
     #[test]
     fn it_works() -> Result<(), Box<dyn std::error::Error>> {
-        let res = parse_chord_line("N    M000:034                 :# Keyboard 5 and %".to_owned())?;
+        let res = parse_chord_line("N    M000:034                 :# Keyboard 5 and %").unwrap();
         assert!(res.output == hid_code("034"));
 
-        parse_chord_line("     LMR0:037+RS              :# Keyboard 8 and *".to_owned());
-        parse_chord_line("     LMMM:String[4]:".to_owned());
-        parse_chord_line("   S LL00:045+RS              :# Keyboard - and _".to_owned());
+        let res = parse_chord_line("     LMR0:037+RS              :# Keyboard 8 and *").unwrap();
+        assert!(res.modifiers == 0x20);
+        let res = parse_chord_line("     LMMM:String[4]:").unwrap();
+        assert!(res.output == string_index("4"));
+        assert!(parse_chord_line("   S LL00:045+RS              :# Keyboard - and _").is_ok());
 
-        let res = parse_string_index("# String[60]=\"650-489-5484\"")?;
+        let res = parse_string_index("# String[60]=\"650-489-5484\"").unwrap();
         assert!(res.0 == 60);
         assert!(res.1 == 12);
 
-        let res = parse_string_index("# String[5]=\"you \"")?;
+        let res = parse_string_index("# String[5]=\"you \"").unwrap();
         assert!(res.0 == 5);
         assert!(res.1 == 4);
 
+        let res = parse_key_value("sleep=900                # Sleep Timeout (seconds)").unwrap();
+        assert!(res == ("sleep", "900"));
+
+        let mut file = std::fs::File::open("configs/CoolHand.txt")?;
+        let (config, _) = parse(&mut file, "CoolHand.txt", Mode::Strict)?;
+        assert!(config.options.sticky_num == Some(false));
+        assert!(config.options.click_mode == Some(true));
+        assert!(config.options.key_repeat == Some(true));
+        assert!(config.options.bluetooth_off == Some(false));
+        assert!(config.settings.sleep == Some(900));
+        assert!(config.settings.mouse_speed == Some(10));
+        assert!(config.settings.repeat_delay == Some(100));
+        assert!(config.mouse.len() == 3);
+        assert!(config.mouse[2].0 == MouseButton::Left);
+        assert!(
+            matches!(&config.mouse[2].1, Some(Chord { output, .. }) if *output == string_index("6"))
+        );
+
         Ok(())
     }
+
+    #[test]
+    fn test_write() -> Result<(), Box<dyn std::error::Error>> {
+        let mut file = std::fs::File::open("configs/CoolHand.txt")?;
+        let (original, _) = parse(&mut file, "CoolHand.txt", Mode::Strict)?;
+
+        // the chords without the mouse buttons from the header
+        let mut layout = Layout::default();
+        for chord in &original.chords {
+            layout.chords.push(layout::Chord {
+                buttons: chord.buttons,
+                action: chord_action(&original, chord)?,
+            });
+        }
+
+        let mut output = std::io::Cursor::new(vec![]);
+        assert!(write(&layout, &mut output)?.is_empty());
+
+        let text = String::from_utf8(output.get_ref().clone())?;
+        assert!(text.contains("     R000:012                 :# Keyboard i and I\n"));
+        assert!(text.contains("N    R000:019+RC              :# Keyboard p and P\n"));
+        assert!(text.contains("# String[5]=\"you \"\n"));
+
+        output.set_position(0);
+        let (written, _) = parse(&mut output, "", Mode::Strict)?;
+        assert!(written.chords.len() == original.chords.len());
+        // String[6] is only used by mouse_left in the header
+        assert!(written.strings.len() == original.strings.len() - 1);
+        for (a, b) in written.chords.iter().zip(original.chords.iter()) {
+            assert!(a.modifiers == b.modifiers);
+            match (&a.output, &b.output) {
+                (ChordOutput::HidCode(a), ChordOutput::HidCode(b)) => {
+                    assert!(a.parse::<u8>()? == b.parse::<u8>()?)
+                }
+                (ChordOutput::StringIndex(a), ChordOutput::StringIndex(b)) => {
+                    assert!(
+                        written.strings[&a.parse::<u32>()?]
+                            == original.strings[&b.parse::<u32>()?]
+                    )
+                }
+                _ => panic!("chord output type changed"),
+            }
+        }
+
+        // strings with nothing Dido can store are skipped, so the output reads back strictly
+        let layout = Layout::new()
+            .with_chord(
+                ButtonState {
+                    f1l: true,
+                    ..Default::default()
+                },
+                Action::String(vec![Action::Mouse(layout::Mouse::click(MouseButton::Left))]),
+            )
+            .with_chord(
+                ButtonState {
+                    f1m: true,
+                    ..Default::default()
+                },
+                Action::String(vec![]),
+            )
+            .with_chord(
+                ButtonState {
+                    f1r: true,
+                    ..Default::default()
+                },
+                Action::keys(&[(0, 0x04)]),
+            );
+        let mut output = std::io::Cursor::new(vec![]);
+        let warnings = write(&layout, &mut output)?;
+        assert!(
+            warnings
+                == vec![
+                    "skipping Mouse left click in String[0], Dido can't store it",
+                    "skipping chord 1 with \"<Mouse left click>\", Dido can't store it",
+                    "skipping chord 2 with \"\", Dido can't store it",
+                ]
+        );
+        output.set_position(0);
+        let (written, _) = parse(&mut output, "", Mode::Strict)?;
+        assert!(written.chords.len() == 1);
+        assert!(written.strings.len() == 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let text = "\
+bluetooth_off=maybe
+# --- end of options
+sleep=900
+# --- end of settings
+mouse_left=false
+# --- end of header
+     R000:012                 :# Keyboard i and I
+     R00:012                  :# Keyboard i and I
+N    R000:019+RX              :# Keyboard p and P
+     M000:300                 :# Keyboard e and E
+     L000:018                 # Keyboard o and O
+     0R00:String[0]:
+# --- end of chords
+# String[0]=\"ab\"
+004
+x05
+# String[1]=\"c\"
+# --- end of config
+";
+
+        let (config, diagnostics) =
+            parse_with_diagnostics(&mut text.as_bytes(), "layout.txt").unwrap();
+        let messages = diagnostics
+            .iter()
+            .map(|d| d.to_string())
+            .collect::<Vec<_>>();
+        assert!(
+            messages
+                == vec![
+                    "layout.txt:1:15: expected true or false, found \"maybe\"",
+                    "layout.txt:5:17: expected a chord line on the next line with what the button does, found nothing",
+                    "layout.txt:8:10: expected ':' after the buttons, found \"012\"",
+                    "layout.txt:9:15: expected modifiers LC, LS, LA, LG, RC, RS, RA or RG, found \"RX\"",
+                    "layout.txt:10:11: expected a key code from 0 to 255, found \"300\"",
+                    "layout.txt:11:31: expected ':' before the comment, found \"#\"",
+                    "layout.txt:16:1: expected a key code, found \"x05\"",
+                    "layout.txt:17:16: expected 1 key line after String[1], found nothing",
+                ]
+        );
+
+        // lenient mode keeps everything it could read
+        assert!(config.options.bluetooth_off == None);
+        assert!(config.settings.sleep == Some(900));
+        assert!(config.chords.len() == 2);
+        assert!(config.strings == BTreeMap::from([(0, vec![(4, 0)]), (1, vec![])]));
+        let (config, diagnostics) =
+            parse(&mut text.as_bytes(), "layout.txt", Mode::Lenient).unwrap();
+        assert!(config.chords.len() == 2);
+        assert!(diagnostics.len() == 8);
+
+        let res = parse(&mut text.as_bytes(), "layout.txt", Mode::Strict);
+        assert!(matches!(res, Err(Error::Diagnostics(diagnostics)) if diagnostics.len() == 8));
+
+        // a string that can't be read doesn't shift the ones after it
+        let text = "\
+# --- end of header
+     R000:String[1]:
+# --- end of chords
+# String[0]=\"ab
+004
+005
+# String[1]=\"c\"
+006
+# String[1]=\"d\"
+007
+# --- end of config
+";
+        let (config, diagnostics) =
+            parse_with_diagnostics(&mut text.as_bytes(), "layout.txt").unwrap();
+        assert!(diagnostics.len() == 2);
+        assert!(
+            diagnostics[1].to_string()
+                == "layout.txt:9:3: expected a String index that isn't used yet, found \"String[1]=\"d\"\""
+        );
+        assert!(config.strings == BTreeMap::from([(1, vec![(6, 0)])]));
+        let (layout, _) = to_layout(&config).unwrap();
+        assert!(layout.chords[0].action == Action::keys(&[(0, 6)]));
+    }
 }
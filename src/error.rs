@@ -0,0 +1,184 @@
+use std::{
+    fmt,
+    io::{Seek, SeekFrom},
+};
+
+use crate::dido::Diagnostic;
+
+/// Where in the input a parse error happened
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Location {
+    /// Byte offset into a binary config
+    Offset(u64),
+    /// Line number (starting at 1) in a text config
+    Line(usize),
+    Unknown,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The input doesn't look like any config format we know
+    UnknownFormat(String),
+    /// The input looks like `format` but couldn't be read
+    Parse {
+        format: &'static str,
+        location: Location,
+        message: String,
+    },
+    /// Every problem found in a text config, when reading it strictly
+    Diagnostics(Vec<Diagnostic>),
+    /// Likely mistakes found when validating a layout
+    Problems(Vec<String>),
+    /// The config can't be turned into the requested format
+    Conversion(String),
+    /// A setting or command is outside what the device accepts
+    InvalidValue(String),
+    /// The output couldn't be written
+    Write(String),
+    Io(std::io::Error),
+}
+
+impl Error {
+    pub(crate) fn parse(format: &'static str, location: Location, message: impl ToString) -> Self {
+        Error::Parse {
+            format,
+            location,
+            message: message.to_string(),
+        }
+    }
+
+    /// A binrw error from reading a binary config, keeping the offset it failed at.
+    /// IO errors don't carry one and binrw rewinds the reader, so running out of
+    /// input is reported at the end of the file
+    pub(crate) fn binary_parse<R: Seek>(
+        format: &'static str,
+        error: binrw::Error,
+        reader: &mut R,
+    ) -> Self {
+        let end = match binrw_root(&error) {
+            binrw::Error::Io(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                reader.seek(SeekFrom::End(0)).ok()
+            }
+            _ => None,
+        };
+        let location = match binrw_offset(&error).or(end) {
+            Some(offset) => Location::Offset(offset),
+            None => Location::Unknown,
+        };
+        Error::parse(format, location, binrw_message(error))
+    }
+
+    pub(crate) fn binary_write(error: binrw::Error) -> Self {
+        match error {
+            binrw::Error::Io(e) => Error::Io(e),
+            error => Error::Write(binrw_message(error)),
+        }
+    }
+}
+
+/// The error under any backtrace binrw wrapped it in
+fn binrw_root(error: &binrw::Error) -> &binrw::Error {
+    match error {
+        binrw::Error::Backtrace(backtrace) => binrw_root(&backtrace.error),
+        error => error,
+    }
+}
+
+fn binrw_offset(error: &binrw::Error) -> Option<u64> {
+    match error {
+        binrw::Error::BadMagic { pos, .. }
+        | binrw::Error::AssertFail { pos, .. }
+        | binrw::Error::Custom { pos, .. }
+        | binrw::Error::NoVariantMatch { pos }
+        | binrw::Error::EnumErrors { pos, .. } => Some(*pos),
+        binrw::Error::Backtrace(backtrace) => binrw_offset(&backtrace.error),
+        _ => None,
+    }
+}
+
+/// The message without binrw's position and backtrace noise
+fn binrw_message(error: binrw::Error) -> String {
+    match error {
+        binrw::Error::AssertFail { message, .. } => message,
+        binrw::Error::Backtrace(backtrace) => binrw_message(*backtrace.error),
+        binrw::Error::NoVariantMatch { .. } | binrw::Error::EnumErrors { .. } => {
+            "unknown command type".to_owned()
+        }
+        binrw::Error::Io(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+            "the file ends early".to_owned()
+        }
+        error => error.to_string(),
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Location::Offset(offset) => write!(f, " at offset {:#x}", offset),
+            Location::Line(line) => write!(f, " on line {}", line),
+            Location::Unknown => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::UnknownFormat(message) => write!(f, "Unknown config format: {}", message),
+            Error::Parse {
+                format,
+                location,
+                message,
+            } => write!(
+                f,
+                "Failed to read {} config{}: {}",
+                format, location, message
+            ),
+            Error::Diagnostics(diagnostics) => {
+                match diagnostics.len() {
+                    1 => write!(f, "Found 1 problem in the config")?,
+                    n => write!(f, "Found {} problems in the config", n)?,
+                }
+                for diagnostic in diagnostics {
+                    write!(f, "\n  {}", diagnostic)?;
+                }
+                Ok(())
+            }
+            Error::Problems(problems) => {
+                match problems.len() {
+                    1 => write!(f, "Found 1 problem in the layout")?,
+                    n => write!(f, "Found {} problems in the layout", n)?,
+                }
+                for problem in problems {
+                    write!(f, "\n  {}", problem)?;
+                }
+                Ok(())
+            }
+            Error::Conversion(message) => write!(f, "Failed to convert config: {}", message),
+            Error::InvalidValue(message) => write!(f, "Invalid value: {}", message),
+            Error::Write(message) => write!(f, "Failed to write config: {}", message),
+            Error::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<csv::Error> for Error {
+    fn from(e: csv::Error) -> Self {
+        Error::Io(e.into())
+    }
+}
@@ -0,0 +1,500 @@
+use std::io::Cursor;
+
+use crate::{csv, dido, error::Error, layout::Layout, twiddler5, twiddler6, twiddler7};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    V5,
+    V6,
+    V7,
+    Csv,
+    Dido,
+}
+
+/// How to read an input config
+#[derive(Default)]
+pub struct ReadOptions {
+    /// Input file name, used to say where problems in text configs are
+    pub name: String,
+    /// Skip detection and read the input as this format
+    pub format: Option<Format>,
+    pub dido_mode: dido::Mode,
+}
+
+/// What to add to a config while writing it
+#[derive(Default)]
+pub struct WriteOptions {
+    /// Thumb key (1-4) that adds shift to chords typing a letter
+    pub generate_caps: Option<i32>,
+    /// Add the default system chords when the format has them
+    pub system_chords: bool,
+}
+
+/// How sure a format is that it can read an input, and why
+#[derive(Debug, Clone, PartialEq)]
+pub struct Detection {
+    /// 0 when the input can't be this format, up to 100
+    pub confidence: u8,
+    pub reason: String,
+}
+
+impl Detection {
+    fn new(confidence: u8, reason: impl ToString) -> Self {
+        Detection {
+            confidence,
+            reason: reason.to_string(),
+        }
+    }
+}
+
+pub type Reader = fn(&[u8], &ReadOptions) -> Result<(Layout, Vec<String>), Error>;
+pub type Writer = fn(Layout, &WriteOptions) -> Result<(Vec<u8>, Vec<String>), Error>;
+
+/// Everything the tool knows about a format. Configs are read into and
+/// written from a `Layout`, formats that can only be read have no writer.
+/// Readers and writers return a warning for everything they drop or change
+pub struct Handler {
+    pub format: Format,
+    pub name: &'static str,
+    pub description: &'static str,
+    pub detect: fn(&[u8]) -> Detection,
+    pub read: Reader,
+    pub write: Option<Writer>,
+}
+
+/// Every format, when two are as sure about an input the first one wins
+pub static REGISTRY: [Handler; 5] = [
+    Handler {
+        format: Format::V5,
+        name: "v5",
+        description: "Twiddler 5 config",
+        detect: detect_v5,
+        read: read_v5,
+        write: Some(write_v5),
+    },
+    Handler {
+        format: Format::V6,
+        name: "v6",
+        description: "Twiddler 6 config",
+        detect: detect_v6,
+        read: read_v6,
+        write: Some(write_v6),
+    },
+    Handler {
+        format: Format::V7,
+        name: "v7",
+        description: "Twiddler 7 config",
+        detect: detect_v7,
+        read: read_v7,
+        write: Some(write_v7),
+    },
+    Handler {
+        format: Format::Dido,
+        name: "dido",
+        description: "Dido text config",
+        detect: detect_dido,
+        read: read_dido,
+        write: Some(write_dido),
+    },
+    Handler {
+        format: Format::Csv,
+        name: "csv",
+        description: "Twiddler Tuner csv",
+        detect: detect_csv,
+        read: read_csv,
+        write: Some(write_csv),
+    },
+];
+
+impl Format {
+    pub fn handler(self) -> &'static Handler {
+        REGISTRY
+            .iter()
+            .find(|handler| handler.format == self)
+            .expect("every format is in the registry")
+    }
+
+    /// Guess the format from a file name like `layout.v6`, `layout_v6.cfg`
+    /// or `layout.txt` for Dido
+    pub fn from_path(path: &std::path::Path) -> Option<Self> {
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or_default();
+        let stem = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or_default();
+
+        if extension.eq_ignore_ascii_case("txt") {
+            return Some(Format::Dido);
+        }
+
+        REGISTRY
+            .iter()
+            .find(|handler| {
+                extension.eq_ignore_ascii_case(handler.name)
+                    || stem.to_ascii_lowercase().ends_with(handler.name)
+            })
+            .map(|handler| handler.format)
+    }
+}
+
+/// How sure each format is about `bytes`, most likely first
+pub fn detect(bytes: &[u8]) -> Vec<(Format, Detection)> {
+    let mut detections = REGISTRY
+        .iter()
+        .map(|handler| (handler.format, (handler.detect)(bytes)))
+        .collect::<Vec<_>>();
+    // stable, so ties keep registry order
+    detections.sort_by_key(|(_, detection)| std::cmp::Reverse(detection.confidence));
+    detections
+}
+
+/// Pick the format for `bytes`, [`detect`] says why
+pub fn pick(bytes: &[u8]) -> Result<Format, Error> {
+    if bytes.is_empty() {
+        return Err(Error::UnknownFormat("the input is empty".to_owned()));
+    }
+
+    match detect(bytes).first() {
+        Some((format, detection)) if detection.confidence > 0 => Ok(*format),
+        _ => Err(Error::UnknownFormat(
+            "it isn't a Twiddler config, Dido text or Twiddler Tuner csv".to_owned(),
+        )),
+    }
+}
+
+fn strip_bom(bytes: &[u8]) -> &[u8] {
+    bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(bytes)
+}
+
+/// The input as text without a byte order mark, None when it isn't UTF-8
+fn text(bytes: &[u8]) -> Option<&str> {
+    std::str::from_utf8(strip_bom(bytes)).ok()
+}
+
+/// The binary formats start with a version byte and a chord count, and the
+/// chord table has to fit in the file
+fn detect_binary(
+    bytes: &[u8],
+    version: u8,
+    version_offset: usize,
+    count_offset: usize,
+    chords_offset: usize,
+    chord_size: usize,
+) -> Detection {
+    match bytes.get(version_offset) {
+        Some(byte) if *byte == version => {}
+        Some(byte) => {
+            return Detection::new(
+                0,
+                format!("byte {} is {:#04x}, not {}", version_offset, byte, version),
+            )
+        }
+        None => return Detection::new(0, format!("shorter than {} bytes", version_offset + 1)),
+    }
+
+    let Some(count) = bytes.get(count_offset..count_offset + 2) else {
+        return Detection::new(
+            20,
+            format!(
+                "byte {} is {} but the header is cut off",
+                version_offset, version
+            ),
+        );
+    };
+    let count = u16::from_le_bytes([count[0], count[1]]) as usize;
+
+    let mut confidence = 50;
+    let mut reason = format!("byte {} is {}", version_offset, version);
+    if version_offset > 0 && bytes[..version_offset].iter().all(|b| *b == 0) {
+        confidence += 10;
+        reason.push_str(&format!(" after {} zero bytes", version_offset));
+    }
+
+    let needed = chords_offset + count * chord_size;
+    if bytes.len() >= needed {
+        confidence += 35;
+        reason.push_str(&format!(
+            " and {} chords fit in {} bytes",
+            count,
+            bytes.len()
+        ));
+    } else {
+        reason.push_str(&format!(
+            " but {} chords need {} bytes and there are {}",
+            count,
+            needed,
+            bytes.len()
+        ));
+    }
+
+    Detection::new(confidence, reason)
+}
+
+fn detect_v5(bytes: &[u8]) -> Detection {
+    detect_binary(bytes, 5, 0, 2, 16, 4)
+}
+
+fn detect_v6(bytes: &[u8]) -> Detection {
+    detect_binary(bytes, 6, 4, 6, 0x28, 8)
+}
+
+fn detect_v7(bytes: &[u8]) -> Detection {
+    detect_binary(bytes, 7, 4, 8, 0x80, 8)
+}
+
+const DIDO_KEYS: [&str; 9] = [
+    "sticky_num",
+    "sticky_shift",
+    "click_mode",
+    "key_repeat",
+    "direct_key",
+    "bluetooth_off",
+    "sleep",
+    "mouse_speed",
+    "repeat_delay",
+];
+
+/// `NACS XXXX:` with legacy finger notation
+fn is_dido_chord_line(line: &str) -> bool {
+    line.get(4..5) == Some(" ")
+        && line.get(9..10) == Some(":")
+        && line
+            .get(5..9)
+            .is_some_and(|fingers| fingers.chars().all(|c| "0LMR".contains(c)))
+}
+
+fn detect_dido(bytes: &[u8]) -> Detection {
+    let Some(text) = text(bytes) else {
+        return Detection::new(0, "not UTF-8 text");
+    };
+
+    if text.lines().any(|line| line.starts_with("# --- end of")) {
+        Detection::new(90, "has Dido section markers like \"# --- end of options\"")
+    } else if let Some(key) = DIDO_KEYS.iter().find(|key| {
+        text.lines()
+            .any(|line| line.trim_start().starts_with(&format!("{}=", key)))
+    }) {
+        Detection::new(70, format!("has the Dido setting {}", key))
+    } else if text.lines().any(is_dido_chord_line) {
+        Detection::new(60, "has Dido chord lines like \"N    R000:019\"")
+    } else if text.starts_with('#') {
+        Detection::new(30, "starts with a # comment")
+    } else {
+        Detection::new(0, "no Dido sections, settings or chord lines")
+    }
+}
+
+fn detect_csv(bytes: &[u8]) -> Detection {
+    let Some(text) = text(bytes) else {
+        return Detection::new(0, "not UTF-8 text");
+    };
+
+    let header = text.lines().next().unwrap_or_default();
+    if header.contains("Keyboard Output") {
+        Detection::new(90, "has the Twiddler Tuner header with Keyboard Output")
+    } else if header.contains(',') {
+        Detection::new(
+            20,
+            "the first line has commas but no Keyboard Output column",
+        )
+    } else {
+        Detection::new(5, "text, csv is the last resort")
+    }
+}
+
+fn read_v5(bytes: &[u8], _options: &ReadOptions) -> Result<(Layout, Vec<String>), Error> {
+    let config = twiddler5::parse(&mut Cursor::new(bytes))?;
+    twiddler5::to_layout(&config)
+}
+
+fn read_v6(bytes: &[u8], _options: &ReadOptions) -> Result<(Layout, Vec<String>), Error> {
+    let config = twiddler6::parse(&mut Cursor::new(bytes))?;
+    Ok(twiddler6::to_layout(&config))
+}
+
+fn read_v7(bytes: &[u8], _options: &ReadOptions) -> Result<(Layout, Vec<String>), Error> {
+    let config = twiddler7::parse(&mut Cursor::new(bytes))?;
+    Ok(twiddler7::to_layout(&config))
+}
+
+fn read_dido(bytes: &[u8], options: &ReadOptions) -> Result<(Layout, Vec<String>), Error> {
+    let (config, diagnostics) =
+        dido::parse(&mut strip_bom(bytes), &options.name, options.dido_mode)?;
+    let mut warnings = diagnostics
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>();
+
+    let (layout, mut layout_warnings) = dido::to_layout(&config)?;
+    warnings.append(&mut layout_warnings);
+    Ok((layout, warnings))
+}
+
+fn read_csv(bytes: &[u8], _options: &ReadOptions) -> Result<(Layout, Vec<String>), Error> {
+    let chords = csv::parse(&mut Cursor::new(strip_bom(bytes)))?;
+    Ok(csv::to_layout(chords))
+}
+
+/// Make the changes every format gets before it's written, saying what was added
+fn prepare(layout: &mut Layout, options: &WriteOptions) -> Vec<String> {
+    let mut messages = vec![];
+    if let Some(t_key) = options.generate_caps {
+        let added = layout.generate_caps(t_key);
+        if added > 0 {
+            messages.push(format!("added {} uppercase chords", added));
+        }
+    }
+    messages
+}
+
+fn write_v5(mut layout: Layout, options: &WriteOptions) -> Result<(Vec<u8>, Vec<String>), Error> {
+    let mut warnings = prepare(&mut layout, options);
+
+    let (config, mut layout_warnings) = twiddler5::from_layout(&layout);
+    warnings.append(&mut layout_warnings);
+
+    let mut output = Cursor::new(vec![]);
+    twiddler5::write(config, &mut output)?;
+    Ok((output.into_inner(), warnings))
+}
+
+fn write_v6(mut layout: Layout, options: &WriteOptions) -> Result<(Vec<u8>, Vec<String>), Error> {
+    let mut warnings = prepare(&mut layout, options);
+    if options.system_chords {
+        layout.ensure_system_chords();
+    }
+
+    let (config, mut layout_warnings) = twiddler6::from_layout(&layout)?;
+    warnings.append(&mut layout_warnings);
+
+    let mut output = Cursor::new(vec![]);
+    warnings.append(&mut twiddler6::write(config, &mut output)?);
+    Ok((output.into_inner(), warnings))
+}
+
+fn write_v7(mut layout: Layout, options: &WriteOptions) -> Result<(Vec<u8>, Vec<String>), Error> {
+    let mut warnings = prepare(&mut layout, options);
+    if options.system_chords {
+        layout.ensure_system_chords();
+    }
+
+    let (config, mut layout_warnings) = twiddler7::from_layout(&layout)?;
+    warnings.append(&mut layout_warnings);
+
+    let mut output = Cursor::new(vec![]);
+    warnings.append(&mut twiddler7::write(config, &mut output)?);
+    Ok((output.into_inner(), warnings))
+}
+
+fn write_dido(mut layout: Layout, options: &WriteOptions) -> Result<(Vec<u8>, Vec<String>), Error> {
+    let mut warnings = prepare(&mut layout, options);
+
+    let mut output = vec![];
+    warnings.append(&mut dido::write(&layout, &mut output)?);
+    Ok((output, warnings))
+}
+
+fn write_csv(mut layout: Layout, options: &WriteOptions) -> Result<(Vec<u8>, Vec<String>), Error> {
+    let mut warnings = prepare(&mut layout, options);
+
+    let mut output = vec![];
+    warnings.append(&mut csv::write(&layout, &mut output)?);
+    Ok((output, warnings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_path() {
+        use std::path::Path;
+
+        assert!(Format::from_path(Path::new("layout.v6")) == Some(Format::V6));
+        assert!(Format::from_path(Path::new("backspicev2_v6.cfg")) == Some(Format::V6));
+        assert!(Format::from_path(Path::new("coolhand_V7.cfg")) == Some(Format::V7));
+        assert!(Format::from_path(Path::new("backspice2_v5.cfg")) == Some(Format::V5));
+        assert!(Format::from_path(Path::new("coolhand.csv")) == Some(Format::Csv));
+        assert!(Format::from_path(Path::new("CoolHand.txt")) == Some(Format::Dido));
+        assert!(Format::from_path(Path::new("layout.cfg")) == None);
+    }
+
+    #[test]
+    fn test_detect() {
+        for (path, format) in [
+            ("configs/backspice2_v5.cfg", Format::V5),
+            ("configs/backspicev2_v6.cfg", Format::V6),
+            ("test/configs/v6/empty.cfg", Format::V6),
+            ("test/configs/v7/default.cfg", Format::V7),
+            ("configs/CoolHand.txt", Format::Dido),
+            ("configs/twiddler_cfg_CoolHand.csv", Format::Csv),
+        ] {
+            let bytes = std::fs::read(path).unwrap();
+            assert!(pick(&bytes).unwrap() == format);
+        }
+
+        // Dido without the leading comment and a csv with a byte order mark
+        let text = std::fs::read_to_string("configs/CoolHand.txt").unwrap();
+        let text = text.lines().skip(6).collect::<Vec<_>>().join("\n");
+        assert!(text.starts_with("sticky_num"));
+        assert!(pick(text.as_bytes()).unwrap() == Format::Dido);
+        let text = text
+            .lines()
+            .filter(|line| line.starts_with(' ') || line.starts_with('N'))
+            .collect::<Vec<_>>()
+            .join("\n");
+        assert!(pick(text.as_bytes()).unwrap() == Format::Dido);
+
+        let csv = std::fs::read("configs/twiddler_cfg_CoolHand.csv").unwrap();
+        assert!(csv.starts_with(b"\xEF\xBB\xBF"));
+        assert!(pick(&csv).unwrap() == Format::Csv);
+        let (chords, _) = read_csv(&csv, &ReadOptions::default()).unwrap();
+        assert!(!chords.chords.is_empty());
+
+        // a v7 header that promises more chords than there are
+        let mut bytes = std::fs::read("test/configs/v7/empty.cfg").unwrap();
+        bytes[8] = 100;
+        let detection = detect_v7(&bytes);
+        assert!(detection.confidence == 60);
+        assert!(detection.reason.contains("100 chords need 928 bytes"));
+
+        assert!(matches!(pick(&[]), Err(Error::UnknownFormat(_))));
+        assert!(matches!(
+            pick(&[0xFF, 0xFE, 0x00]),
+            Err(Error::UnknownFormat(_))
+        ));
+    }
+
+    #[test]
+    fn test_generate_caps() {
+        use crate::layout::Action;
+
+        let bytes = std::fs::read("configs/twiddler_cfg_CoolHand.csv").unwrap();
+        let (layout, _) = read_csv(&bytes, &ReadOptions::default()).unwrap();
+        let options = WriteOptions {
+            generate_caps: Some(4),
+            system_chords: false,
+        };
+
+        // "","1R","i" comes back as I on T4 whatever the output format
+        for handler in &REGISTRY {
+            let (written, warnings) = (handler.write.unwrap())(layout.clone(), &options).unwrap();
+            assert!(warnings[0] == "added 29 uppercase chords");
+            let (read, _) = (handler.read)(&written, &ReadOptions::default()).unwrap();
+            assert!(
+                read.chords.iter().any(|chord| chord.buttons.t4
+                    && chord.buttons.f1r
+                    && chord.action
+                        == Action::Key {
+                            modifier: 0x02,
+                            key_code: 0x0C,
+                        }),
+                "no caps in {}",
+                handler.name
+            );
+        }
+    }
+}
@@ -3,7 +3,7 @@ use std::collections::HashMap;
 use std::ops::RangeInclusive;
 use std::sync::OnceLock;
 
-fn modifiers_hid() -> &'static HashMap<&'static str, usize> {
+pub fn modifiers_hid() -> &'static HashMap<&'static str, usize> {
     static MODIFIERS: OnceLock<HashMap<&str, usize>> = OnceLock::new();
     MODIFIERS.get_or_init(|| {
         let mut m = HashMap::new();
@@ -77,6 +77,7 @@ pub fn keys_hid() -> &'static BiMap<u8, String> {
         elements.insert(0x29, "Escape".to_owned());
         elements.insert(0x2A, "Backspace".to_owned());
         elements.insert(0x2B, "Tab".to_owned());
+        elements.insert(0x2C, "Space".to_owned());
         elements.insert(0x39, "CapsLock".to_owned());
         elements.insert(0x46, "PrintScreen".to_owned());
         elements.insert(0x47, "ScrollLock".to_owned());
@@ -97,3 +98,48 @@ pub fn keys_hid() -> &'static BiMap<u8, String> {
         elements
     })
 }
+
+/// Characters on a US keyboard and the (modifier, key code) pair that types them
+pub fn ascii_hid() -> &'static BiMap<char, (u8, u8)> {
+    static ASCII: OnceLock<BiMap<char, (u8, u8)>> = OnceLock::new();
+    ASCII.get_or_init(|| {
+        let mut elements: bimap::BiMap<char, (u8, u8)> = BiMap::new();
+
+        // Alpha keys
+        for i in 0x04..=0x1D {
+            elements.insert((i - 0x04 + b'a') as char, (0, i));
+            elements.insert((i - 0x04 + b'A') as char, (0x2, i));
+        }
+
+        // numbers and their shifted symbols
+        for (i, (c, shifted)) in "1234567890".chars().zip("!@#$%^&*()".chars()).enumerate() {
+            elements.insert(c, (0, 0x1E + i as u8));
+            elements.insert(shifted, (0x2, 0x1E + i as u8));
+        }
+
+        // punctuation and their shifted symbols
+        for (key_code, c, shifted) in [
+            (0x2D, '-', '_'),
+            (0x2E, '=', '+'),
+            (0x2F, '[', '{'),
+            (0x30, ']', '}'),
+            (0x31, '\\', '|'),
+            (0x33, ';', ':'),
+            (0x34, '\'', '"'),
+            (0x35, '`', '~'),
+            (0x36, ',', '<'),
+            (0x37, '.', '>'),
+            (0x38, '/', '?'),
+        ] {
+            elements.insert(c, (0, key_code));
+            elements.insert(shifted, (0x2, key_code));
+        }
+
+        // whitespace
+        elements.insert('\n', (0, 0x28));
+        elements.insert('\t', (0, 0x2B));
+        elements.insert(' ', (0, 0x2C));
+
+        elements
+    })
+}
@@ -0,0 +1,1034 @@
+use std::{fmt, time::Duration};
+
+use crate::{
+    buttons::{ButtonState, GridStyle, MouseButton},
+    error::Error,
+    hid,
+};
+
+/// A layout independent of any config format. Every format is read into a
+/// `Layout` and written from one, so changes like generating caps are made here once.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Layout {
+    pub settings: Settings,
+    pub chords: Vec<Chord>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chord {
+    pub buttons: ButtonState,
+    pub action: Action,
+}
+
+impl Chord {
+    /// The chord's buttons drawn as a grid, see [`ButtonState::grid`], with
+    /// what it does next to the thumb row
+    pub fn render(&self, style: GridStyle) -> String {
+        let mut lines = self.buttons.grid(style);
+        lines[1] = format!("{}  {}", lines[1], self.action);
+        lines
+            .iter()
+            .map(|line| line.trim_end())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// What a chord does when it's pressed
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    /// A key press, `modifier` has the bits of a HID report so 0x02 is left shift
+    Key {
+        modifier: u8,
+        key_code: u8,
+    },
+    /// Actions run one after another, mostly keys typing a word
+    String(Vec<Action>),
+    Mouse(Mouse),
+    Delay(Duration),
+    System(SystemCommand),
+    /// A chord that does nothing
+    None,
+}
+
+impl Action {
+    pub fn key(modifier: u8, key_code: u8) -> Self {
+        Action::Key { modifier, key_code }
+    }
+
+    /// A string typing each `(modifier, key_code)` in turn
+    pub fn keys(keys: &[(u8, u8)]) -> Self {
+        Action::String(
+            keys.iter()
+                .map(|(modifier, key_code)| Action::key(*modifier, *key_code))
+                .collect(),
+        )
+    }
+
+    /// The actions this one runs, with strings inside strings expanded since
+    /// no format can store those
+    pub fn flatten(&self) -> Vec<&Action> {
+        match self {
+            Action::String(actions) => actions.iter().flat_map(Action::flatten).collect(),
+            action => vec![action],
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    /// Actions the way `dump` and `diff` show them. Keys are written like in
+    /// CSV configs, "A", "<Return>" or "<L-Ctrl>p", and strings like "\"you \""
+    /// with anything but keys in angle brackets
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Key { modifier, key_code } => {
+                write!(f, "{}", key_text(*modifier, *key_code, false))
+            }
+            Action::String(_) => {
+                let mut text = String::new();
+                for action in self.flatten() {
+                    match action {
+                        Action::Key { modifier, key_code } => {
+                            text.push_str(&key_text(*modifier, *key_code, true))
+                        }
+                        action => text.push_str(&format!("<{}>", action)),
+                    }
+                }
+                write!(f, "\"{}\"", text)
+            }
+            Action::Mouse(mouse) => write!(f, "{}", mouse),
+            Action::Delay(delay) => write!(f, "Delay {}ms", delay.as_millis()),
+            Action::System(command) => write!(f, "{}", command),
+            Action::None => write!(f, "Nothing"),
+        }
+    }
+}
+
+/// A key with its modifiers in front, like "<L-Ctrl>p" or "<Return>". Shift is
+/// left out of keys that type a character, spaces only count as one inside strings
+fn key_text(modifier: u8, key_code: u8, in_string: bool) -> String {
+    let mut modifier = modifier;
+
+    let mut key = None;
+    for shift in [modifier & 0x2, 0] {
+        if let Some(c) = hid::ascii_hid().get_by_right(&(shift, key_code)) {
+            if !c.is_whitespace() || (in_string && *c == ' ') {
+                key = Some(c.to_string());
+                modifier &= !shift;
+                break;
+            }
+        }
+    }
+    let key = key.unwrap_or_else(|| match hid::keys_hid().get_by_left(&key_code) {
+        Some(name) => format!("<{}>", name),
+        None => format!("<HIDCode 0x{:02X}>", key_code),
+    });
+
+    let mut modifiers = hid::modifiers_hid()
+        .iter()
+        .filter(|(_, value)| modifier & **value as u8 != 0)
+        .collect::<Vec<_>>();
+    modifiers.sort_by_key(|(_, value)| **value);
+
+    let modifiers = modifiers
+        .iter()
+        .map(|(name, _)| format!("<{}>", name))
+        .collect::<String>();
+    format!("{}{}", modifiers, key)
+}
+
+/// Mouse buttons a chord presses
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Mouse {
+    pub left: bool,
+    pub middle: bool,
+    pub right: bool,
+    pub action: MouseAction,
+}
+
+impl Mouse {
+    pub fn click(button: MouseButton) -> Self {
+        Self {
+            left: button == MouseButton::Left,
+            middle: button == MouseButton::Middle,
+            right: button == MouseButton::Right,
+            action: MouseAction::Click,
+        }
+    }
+}
+
+impl fmt::Display for Mouse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let buttons = [
+            ("left", self.left),
+            ("middle", self.middle),
+            ("right", self.right),
+        ]
+        .iter()
+        .filter(|(_, pressed)| *pressed)
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>();
+
+        let buttons = if buttons.is_empty() {
+            "no buttons".to_owned()
+        } else {
+            buttons.join("+")
+        };
+        match self.action {
+            MouseAction::Click => write!(f, "Mouse {} click", buttons),
+            MouseAction::Unknown(action) => write!(f, "Mouse {} action {}", buttons, action),
+        }
+    }
+}
+
+/// What a mouse command does with its buttons, only clicks have been seen so far
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum MouseAction {
+    /// The buttons go down when the command runs and come back up when the
+    /// chord is released. In a command list they stay down until a later
+    /// mouse command changes them, so press, delay, release is a click.
+    #[default]
+    Click,
+    Unknown(u8),
+}
+
+impl From<u8> for MouseAction {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => MouseAction::Click,
+            value => MouseAction::Unknown(value),
+        }
+    }
+}
+
+impl From<MouseAction> for u8 {
+    fn from(action: MouseAction) -> Self {
+        match action {
+            MouseAction::Click => 0,
+            MouseAction::Unknown(value) => value,
+        }
+    }
+}
+
+/// Device functions a chord can run instead of typing, codes we don't know are kept as is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SystemCommand {
+    Sleep,
+    LedKeyboardFlags,
+    ConfigCycle,
+    BluetoothHostsCycle,
+    BluetoothHostsClear,
+    LedBatteryLevel,
+    NavModeCycle,
+    PrintStatus,
+    Unknown(u8),
+}
+
+impl From<u8> for SystemCommand {
+    fn from(code: u8) -> Self {
+        match code {
+            1 => SystemCommand::Sleep,
+            2 => SystemCommand::LedKeyboardFlags,
+            4 => SystemCommand::ConfigCycle,
+            5 => SystemCommand::BluetoothHostsCycle,
+            6 => SystemCommand::BluetoothHostsClear,
+            10 => SystemCommand::LedBatteryLevel,
+            11 => SystemCommand::NavModeCycle,
+            12 => SystemCommand::PrintStatus,
+            code => SystemCommand::Unknown(code),
+        }
+    }
+}
+
+impl From<SystemCommand> for u8 {
+    fn from(command: SystemCommand) -> Self {
+        match command {
+            SystemCommand::Sleep => 1,
+            SystemCommand::LedKeyboardFlags => 2,
+            SystemCommand::ConfigCycle => 4,
+            SystemCommand::BluetoothHostsCycle => 5,
+            SystemCommand::BluetoothHostsClear => 6,
+            SystemCommand::LedBatteryLevel => 10,
+            SystemCommand::NavModeCycle => 11,
+            SystemCommand::PrintStatus => 12,
+            SystemCommand::Unknown(code) => code,
+        }
+    }
+}
+
+impl std::fmt::Display for SystemCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SystemCommand::Sleep => write!(f, "Sleep now"),
+            SystemCommand::LedKeyboardFlags => write!(f, "LED: Keyboard Flags"),
+            SystemCommand::ConfigCycle => write!(f, "Config cycle"),
+            SystemCommand::BluetoothHostsCycle => write!(f, "Bluetooth hosts: cycle"),
+            SystemCommand::BluetoothHostsClear => write!(f, "Bluetooth hosts: clear"),
+            SystemCommand::LedBatteryLevel => write!(f, "LED: Battery Level"),
+            SystemCommand::NavModeCycle => write!(f, "Nav mode: cycle"),
+            SystemCommand::PrintStatus => write!(f, "Print status to keyboard"),
+            SystemCommand::Unknown(code) => write!(f, "Unknown system command {}", code),
+        }
+    }
+}
+
+/// Device settings, the defaults are the ones a new v7 config has
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    pub key_repeat: bool,
+    /// How long a chord is held before it repeats, the device counts in 10ms steps
+    pub repeat_delay: Duration,
+    /// How long the device waits before sleeping
+    pub idle_time: Duration,
+    /// Mouse speed as shown on the device, 1 is slowest and 254 fastest
+    pub mouse_speed: u8,
+    /// Mouse speed for each step the joystick is pushed
+    pub mouse_accel_table: [u8; 20],
+    pub haptic: bool,
+    pub direct: bool,
+    pub sticky_num: bool,
+    pub sticky_alt: bool,
+    pub sticky_ctrl: bool,
+    pub sticky_shift: bool,
+    /// Mouse buttons on F0L instead of F0R
+    pub left_mouse_pos: bool,
+    /// The joystick clicks the left button, v5 and Dido can make it the right one
+    pub left_click: bool,
+    /// Only v5 and Dido can turn the Bluetooth radio off
+    pub bluetooth_off: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            key_repeat: true,
+            repeat_delay: Duration::from_millis(1000),
+            idle_time: Duration::from_secs(600),
+            mouse_speed: 0x80,
+            mouse_accel_table: [
+                0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0C, 0x0D, 0x0F,
+                0x11, 0x14, 0x16, 0x18, 0x1A, 0x1D,
+            ],
+            haptic: true,
+            direct: false,
+            sticky_num: false,
+            sticky_alt: false,
+            sticky_ctrl: false,
+            sticky_shift: false,
+            left_mouse_pos: false,
+            left_click: true,
+            bluetooth_off: false,
+        }
+    }
+}
+
+impl Settings {
+    /// `idle_time` in the whole seconds the binary formats store
+    pub fn idle_time_secs(&self, warnings: &mut Vec<String>) -> u16 {
+        let secs = u32::try_from(self.idle_time.as_secs()).unwrap_or(u32::MAX);
+        clamp_setting("idle time (seconds)", secs, 0, u16::MAX as u32, warnings) as u16
+    }
+
+    /// `repeat_delay` in the 10ms steps the binary formats store
+    pub fn repeat_delay_steps(&self, warnings: &mut Vec<String>) -> u8 {
+        let steps = u32::try_from(self.repeat_delay.as_millis() / 10).unwrap_or(u32::MAX);
+        clamp_setting("key repeat delay (10ms steps)", steps, 1, 250, warnings) as u8
+    }
+
+    /// `mouse_speed` in the 1-254 range v6 and v7 devices accept
+    pub fn device_mouse_speed(&self, warnings: &mut Vec<String>) -> u8 {
+        clamp_setting("mouse speed", self.mouse_speed as u32, 1, 254, warnings) as u8
+    }
+
+    /// Settings read in 10ms steps, like the binary formats and Dido store them
+    pub fn repeat_delay_from_steps(steps: u32) -> Duration {
+        Duration::from_millis(steps as u64 * 10)
+    }
+
+    /// Change the settings in `changes`, after checking they're in range
+    pub fn apply(&mut self, changes: &SettingsChanges) -> Result<(), Error> {
+        changes.validate()?;
+
+        let flags = [
+            (changes.key_repeat, &mut self.key_repeat),
+            (changes.haptic, &mut self.haptic),
+            (changes.direct, &mut self.direct),
+            (changes.sticky_num, &mut self.sticky_num),
+            (changes.sticky_alt, &mut self.sticky_alt),
+            (changes.sticky_ctrl, &mut self.sticky_ctrl),
+            (changes.sticky_shift, &mut self.sticky_shift),
+            (changes.left_mouse_pos, &mut self.left_mouse_pos),
+        ];
+        for (change, flag) in flags {
+            if let Some(on) = change {
+                *flag = on;
+            }
+        }
+
+        if let Some(idle_time) = changes.idle_time {
+            self.idle_time = idle_time;
+        }
+        if let Some(speed) = changes.mouse_speed {
+            self.mouse_speed = speed;
+        }
+        if let Some(delay) = changes.repeat_delay {
+            self.repeat_delay = delay;
+        }
+
+        Ok(())
+    }
+
+    /// The on or off settings by name
+    pub fn flags(&self) -> [(&'static str, bool); 10] {
+        [
+            ("key repeat", self.key_repeat),
+            ("haptic", self.haptic),
+            ("direct", self.direct),
+            ("sticky num", self.sticky_num),
+            ("sticky alt", self.sticky_alt),
+            ("sticky ctrl", self.sticky_ctrl),
+            ("sticky shift", self.sticky_shift),
+            ("left mouse pos", self.left_mouse_pos),
+            ("left click", self.left_click),
+            ("bluetooth off", self.bluetooth_off),
+        ]
+    }
+
+    /// The other settings by name, with their values in human units
+    pub fn values(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("repeat delay", format_duration(self.repeat_delay)),
+            ("idle time", format_duration(self.idle_time)),
+            ("mouse speed", self.mouse_speed.to_string()),
+            (
+                "mouse accel table",
+                self.mouse_accel_table
+                    .iter()
+                    .map(|step| step.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            ),
+        ]
+    }
+
+    /// Every setting by name, flags as "on" or "off"
+    pub fn entries(&self) -> Vec<(&'static str, String)> {
+        let flags = self
+            .flags()
+            .into_iter()
+            .map(|(name, on)| (name, if on { "on" } else { "off" }.to_owned()));
+        flags.chain(self.values()).collect()
+    }
+}
+
+/// Settings to change in a config, the ones left out stay as they are.
+/// Values are in the units the device shows and checked against what it can store
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SettingsChanges {
+    pub key_repeat: Option<bool>,
+    pub haptic: Option<bool>,
+    pub direct: Option<bool>,
+    pub sticky_num: Option<bool>,
+    pub sticky_alt: Option<bool>,
+    pub sticky_ctrl: Option<bool>,
+    pub sticky_shift: Option<bool>,
+    /// Mouse buttons on F0L instead of F0R
+    pub left_mouse_pos: Option<bool>,
+    pub idle_time: Option<Duration>,
+    /// Mouse speed as shown on the device, 1 is slowest and 254 fastest
+    pub mouse_speed: Option<u8>,
+    pub repeat_delay: Option<Duration>,
+}
+
+impl SettingsChanges {
+    pub fn is_empty(&self) -> bool {
+        *self == SettingsChanges::default()
+    }
+
+    /// Check every value fits what v6 and v7 configs can store
+    pub fn validate(&self) -> Result<(), Error> {
+        if let Some(idle_time) = self.idle_time {
+            if idle_time.subsec_millis() != 0 || idle_time.as_secs() > u16::MAX as u64 {
+                return Err(Error::InvalidValue(format!(
+                    "idle time must be whole seconds up to 65535s, not {}",
+                    format_duration(idle_time)
+                )));
+            }
+        }
+
+        if let Some(speed) = self.mouse_speed {
+            if !(1..=254).contains(&speed) {
+                return Err(Error::InvalidValue(format!(
+                    "mouse sensitivity must be 1-254, not {}",
+                    speed
+                )));
+            }
+        }
+
+        if let Some(delay) = self.repeat_delay {
+            let millis = delay.as_millis();
+            if millis % 10 != 0 || !(10..=2500).contains(&millis) {
+                return Err(Error::InvalidValue(format!(
+                    "repeat delay must be 10ms to 2500ms in 10ms steps, not {}",
+                    format_duration(delay)
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A duration in the largest unit that shows it exactly, like "10m" or "300ms"
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if duration.subsec_millis() != 0 || secs == 0 {
+        format!("{}ms", duration.as_millis())
+    } else if secs.is_multiple_of(3600) {
+        format!("{}h", secs / 3600)
+    } else if secs.is_multiple_of(60) {
+        format!("{}m", secs / 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+/// Clamp a setting to what the device can store, adding a warning when it's out of range
+pub(crate) fn clamp_setting(
+    name: &str,
+    value: u32,
+    min: u32,
+    max: u32,
+    warnings: &mut Vec<String>,
+) -> u32 {
+    if value < min || value > max {
+        let clamped = value.clamp(min, max);
+        warnings.push(format!(
+            "{} {} is outside the range {}-{}, using {}",
+            name, value, min, max, clamped
+        ));
+        clamped
+    } else {
+        value
+    }
+}
+
+impl Layout {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_settings(mut self, settings: Settings) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    pub fn with_chord(mut self, buttons: ButtonState, action: Action) -> Self {
+        self.chords.push(Chord { buttons, action });
+        self
+    }
+
+    /// Add an upper case copy of every chord that types a letter without
+    /// thumb buttons, with thumb key `t_key` (1-4) acting as shift. Returns
+    /// how many chords were added
+    pub fn generate_caps(&mut self, t_key: i32) -> usize {
+        let mut new_chords = vec![];
+        for chord in &self.chords {
+            let buttons = chord.buttons;
+            if buttons.t0 || buttons.t1 || buttons.t2 || buttons.t3 || buttons.t4 {
+                continue;
+            }
+
+            if let Action::Key { modifier, key_code } = chord.action {
+                if hid::ALPHA_HID_CODES.contains(&key_code) {
+                    let mut chord = chord.clone();
+
+                    match t_key {
+                        1 => chord.buttons.t1 = true,
+                        2 => chord.buttons.t2 = true,
+                        3 => chord.buttons.t3 = true,
+                        4 => chord.buttons.t4 = true,
+                        _ => {}
+                    }
+
+                    chord.action = Action::Key {
+                        modifier: modifier | 0x2, // Add left shift
+                        key_code,
+                    };
+
+                    new_chords.push(chord);
+                }
+            }
+        }
+
+        let added = new_chords.len();
+        self.chords.append(&mut new_chords);
+        added
+    }
+
+    /// Add the system chords a new v6 or v7 config has, unless they're already there
+    pub fn ensure_system_chords(&mut self) {
+        let system_chords = [
+            (
+                SystemCommand::BluetoothHostsClear,
+                ButtonState {
+                    t1: true,
+                    t4: true,
+                    f4r: true,
+                    ..Default::default()
+                },
+            ),
+            (
+                SystemCommand::LedKeyboardFlags,
+                ButtonState {
+                    t0: true,
+                    f1r: true,
+                    ..Default::default()
+                },
+            ),
+            (
+                SystemCommand::LedBatteryLevel,
+                ButtonState {
+                    t0: true,
+                    f1m: true,
+                    ..Default::default()
+                },
+            ),
+            (
+                SystemCommand::PrintStatus,
+                ButtonState {
+                    t0: true,
+                    f1l: true,
+                    ..Default::default()
+                },
+            ),
+            (
+                SystemCommand::Sleep,
+                ButtonState {
+                    t0: true,
+                    t2: true,
+                    t3: true,
+                    ..Default::default()
+                },
+            ),
+            (
+                SystemCommand::BluetoothHostsCycle,
+                ButtonState {
+                    t0: true,
+                    f4r: true,
+                    ..Default::default()
+                },
+            ),
+            (
+                SystemCommand::ConfigCycle,
+                ButtonState {
+                    t0: true,
+                    f4m: true,
+                    ..Default::default()
+                },
+            ),
+            (
+                SystemCommand::NavModeCycle,
+                ButtonState {
+                    t0: true,
+                    f4l: true,
+                    ..Default::default()
+                },
+            ),
+        ];
+
+        for (command, buttons) in system_chords {
+            let chord = Chord {
+                buttons,
+                action: Action::System(command),
+            };
+            if !self.chords.contains(&chord) {
+                self.chords.push(chord);
+            }
+        }
+    }
+
+    /// Things that are most likely mistakes: chords without buttons, chords
+    /// hidden by an earlier one with the same buttons and strings that type nothing.
+    /// Chords are numbered from 1
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        for (i, chord) in self.chords.iter().enumerate() {
+            if chord.buttons == ButtonState::default() {
+                problems.push(format!("chord {} has no buttons", i + 1));
+            } else if let Some(first) = self.chords[..i]
+                .iter()
+                .position(|other| other.buttons == chord.buttons)
+            {
+                problems.push(format!(
+                    "chord {} ({}) has the same buttons as chord {}",
+                    i + 1,
+                    chord.buttons,
+                    first + 1
+                ));
+            }
+
+            if matches!(chord.action, Action::String(_)) && chord.action.flatten().is_empty() {
+                problems.push(format!("chord {} types an empty string", i + 1));
+            }
+        }
+        problems
+    }
+
+    /// What changed from this layout to `other`. Chords are matched by their
+    /// buttons, in order when several have the same ones
+    pub fn diff(&self, other: &Layout) -> Vec<Difference> {
+        let mut differences = vec![];
+
+        let new_settings = other.settings.entries();
+        for ((name, old), (_, new)) in self.settings.entries().into_iter().zip(new_settings) {
+            if old != new {
+                differences.push(Difference::Setting { name, old, new });
+            }
+        }
+
+        let mut matched = vec![false; other.chords.len()];
+        for chord in &self.chords {
+            let found = other
+                .chords
+                .iter()
+                .enumerate()
+                .position(|(i, new)| !matched[i] && new.buttons == chord.buttons);
+            match found {
+                Some(i) => {
+                    matched[i] = true;
+                    if other.chords[i].action != chord.action {
+                        differences.push(Difference::Changed {
+                            buttons: chord.buttons,
+                            old: chord.action.clone(),
+                            new: other.chords[i].action.clone(),
+                        });
+                    }
+                }
+                None => differences.push(Difference::Removed(chord.clone())),
+            }
+        }
+
+        for (chord, matched) in other.chords.iter().zip(matched) {
+            if !matched {
+                differences.push(Difference::Added(chord.clone()));
+            }
+        }
+
+        differences
+    }
+}
+
+/// Read a duration like "300ms", "90s", "10m" or "1h"
+pub fn parse_duration(text: &str) -> Result<Duration, Error> {
+    let invalid = || {
+        Error::InvalidValue(format!(
+            "expected a duration like 300ms, 90s or 10m, not \"{}\"",
+            text
+        ))
+    };
+
+    let (number, unit) = text.split_at(
+        text.find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(text.len()),
+    );
+    let number = number.parse::<u64>().map_err(|_| invalid())?;
+    let millis = match unit {
+        "ms" => Some(number),
+        "s" => number.checked_mul(1000),
+        "m" => number.checked_mul(60 * 1000),
+        "h" => number.checked_mul(60 * 60 * 1000),
+        _ => None,
+    };
+
+    millis.map(Duration::from_millis).ok_or_else(invalid)
+}
+
+/// One way two layouts differ
+#[derive(Debug, Clone, PartialEq)]
+pub enum Difference {
+    Setting {
+        name: &'static str,
+        old: String,
+        new: String,
+    },
+    Added(Chord),
+    Removed(Chord),
+    /// The same buttons do something else
+    Changed {
+        buttons: ButtonState,
+        old: Action,
+        new: Action,
+    },
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Difference::Setting { name, old, new } => write!(f, "~ {}: {} -> {}", name, old, new),
+            Difference::Added(chord) => write!(f, "+ {}: {}", chord.buttons, chord.action),
+            Difference::Removed(chord) => write!(f, "- {}: {}", chord.buttons, chord.action),
+            Difference::Changed { buttons, old, new } => {
+                write!(f, "~ {}: {} -> {}", buttons, old, new)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_caps() {
+        let mut layout = Layout::default();
+        layout.chords.push(Chord {
+            buttons: ButtonState {
+                f1r: true,
+                ..Default::default()
+            },
+            action: Action::Key {
+                modifier: 0,
+                key_code: 0x0C,
+            },
+        });
+        // not a letter
+        layout.chords.push(Chord {
+            buttons: ButtonState {
+                f1m: true,
+                ..Default::default()
+            },
+            action: Action::Key {
+                modifier: 0,
+                key_code: 0x2C,
+            },
+        });
+        // already uses a thumb button
+        layout.chords.push(Chord {
+            buttons: ButtonState {
+                t1: true,
+                f1r: true,
+                ..Default::default()
+            },
+            action: Action::Key {
+                modifier: 0,
+                key_code: 0x13,
+            },
+        });
+
+        assert!(layout.generate_caps(4) == 1);
+        assert!(layout.chords.len() == 4);
+        let chord = layout.chords.last().unwrap();
+        assert!(chord.buttons.t4 == true);
+        assert!(chord.buttons.f1r == true);
+        assert!(
+            chord.action
+                == Action::Key {
+                    modifier: 0x02,
+                    key_code: 0x0C,
+                }
+        );
+    }
+
+    #[test]
+    fn test_system_chords() {
+        let mut layout = Layout::default();
+        layout.ensure_system_chords();
+        assert!(layout.chords.len() == 8);
+        assert!(layout
+            .chords
+            .iter()
+            .all(|chord| matches!(chord.action, Action::System(_))));
+
+        // nothing to add the second time
+        layout.ensure_system_chords();
+        assert!(layout.chords.len() == 8);
+    }
+
+    #[test]
+    fn test_flatten() {
+        let key = Action::Key {
+            modifier: 0,
+            key_code: 0x04,
+        };
+        let action = Action::String(vec![
+            key.clone(),
+            Action::String(vec![key.clone(), Action::Delay(Duration::from_millis(10))]),
+        ]);
+        assert!(action.flatten().len() == 3);
+        assert!(key.flatten() == vec![&key]);
+
+        let mut warnings = vec![];
+        assert!(Settings::default().repeat_delay_steps(&mut warnings) == 100);
+        assert!(Settings::default().idle_time_secs(&mut warnings) == 600);
+        assert!(warnings.is_empty());
+        assert!(clamp_setting("mouse speed", 300, 1, 254, &mut warnings) == 254);
+        assert!(warnings == vec!["mouse speed 300 is outside the range 1-254, using 254"]);
+    }
+
+    #[test]
+    fn test_display() {
+        assert!(Action::key(0x02, 0x04).to_string() == "A");
+        assert!(Action::key(0x01, 0x13).to_string() == "<L-Ctrl>p");
+        assert!(Action::key(0, 0x2C).to_string() == "<Space>");
+        assert!(Action::key(0x20, 0x28).to_string() == "<R-Shift><Return>");
+        assert!(Action::key(0, 0xF0).to_string() == "<HIDCode 0xF0>");
+        assert!(
+            Action::keys(&[(0, 0x1C), (0, 0x12), (0, 0x18), (0, 0x2C)]).to_string() == "\"you \""
+        );
+
+        let action = Action::String(vec![
+            Action::key(0x01, 0x06),
+            Action::Delay(Duration::from_millis(100)),
+            Action::key(0, 0x28),
+        ]);
+        assert!(action.to_string() == "\"<L-Ctrl>c<Delay 100ms><Return>\"");
+        assert!(Action::Mouse(Mouse::click(MouseButton::Right)).to_string() == "Mouse right click");
+
+        let buttons = ButtonState {
+            t1: true,
+            t4: true,
+            f1r: true,
+            f2m: true,
+            ..Default::default()
+        };
+        assert!(buttons.to_string() == "T1 T4 1R 2M");
+        assert!(ButtonState::default().to_string() == "(none)");
+
+        assert!(format_duration(Duration::from_secs(600)) == "10m");
+        assert!(format_duration(Duration::from_secs(90)) == "90s");
+        assert!(format_duration(Duration::from_millis(300)) == "300ms");
+    }
+
+    #[test]
+    fn test_settings_changes() {
+        assert!(parse_duration("300ms").unwrap() == Duration::from_millis(300));
+        assert!(parse_duration("10m").unwrap() == Duration::from_secs(600));
+        assert!(parse_duration("2h").unwrap() == Duration::from_secs(7200));
+        assert!(parse_duration("90").is_err());
+        assert!(parse_duration("ms").is_err());
+        assert!(parse_duration("10 m").is_err());
+
+        let mut settings = Settings::default();
+        let changes = SettingsChanges {
+            haptic: Some(false),
+            sticky_shift: Some(true),
+            idle_time: Some(Duration::from_secs(600)),
+            mouse_speed: Some(80),
+            repeat_delay: Some(Duration::from_millis(300)),
+            ..Default::default()
+        };
+        settings.apply(&changes).unwrap();
+        assert!(settings.haptic == false);
+        assert!(settings.sticky_shift == true);
+        assert!(settings.key_repeat == true);
+        assert!(settings.mouse_speed == 80);
+        assert!(settings.repeat_delay_steps(&mut vec![]) == 30);
+
+        for changes in [
+            SettingsChanges {
+                mouse_speed: Some(255),
+                ..Default::default()
+            },
+            SettingsChanges {
+                repeat_delay: Some(Duration::from_millis(305)),
+                ..Default::default()
+            },
+            SettingsChanges {
+                repeat_delay: Some(Duration::from_secs(3)),
+                ..Default::default()
+            },
+            SettingsChanges {
+                idle_time: Some(Duration::from_secs(70000)),
+                ..Default::default()
+            },
+        ] {
+            assert!(settings.apply(&changes).is_err());
+        }
+        assert!(SettingsChanges::default().is_empty() == true);
+    }
+
+    #[test]
+    fn test_render() {
+        let chord = Chord {
+            buttons: ButtonState {
+                t1: true,
+                f1r: true,
+                f4l: true,
+                ..Default::default()
+            },
+            action: Action::key(0x01, 0x13),
+        };
+        let expected = [
+            "    T0  T1  T2  T3  T4",
+            "T  [ ] [X] [ ] [ ] [ ]  <L-Ctrl>p",
+            "    L   M   R",
+            "0  [ ] [ ] [ ]",
+            "1  [ ] [ ] [X]",
+            "2  [ ] [ ] [ ]",
+            "3  [ ] [ ] [ ]",
+            "4  [X] [ ] [ ]",
+        ];
+        assert!(chord.render(GridStyle::Ascii) == expected.join("\n"));
+
+        let lines = chord.buttons.grid(GridStyle::Unicode);
+        assert!(lines.len() == 8);
+        assert!(lines[1].trim_end() == "T   □   ■   □   □   □");
+        assert!(lines
+            .iter()
+            .all(|line| line.chars().count() == lines[0].chars().count()));
+    }
+
+    #[test]
+    fn test_diff() {
+        let a = ButtonState {
+            f1r: true,
+            ..Default::default()
+        };
+        let b = ButtonState {
+            f1m: true,
+            ..Default::default()
+        };
+        let c = ButtonState {
+            f1l: true,
+            ..Default::default()
+        };
+        let old = Layout::new()
+            .with_chord(a, Action::key(0, 0x04))
+            .with_chord(b, Action::key(0, 0x05));
+        let settings = Settings {
+            haptic: false,
+            ..Default::default()
+        };
+        let new = Layout::new()
+            .with_settings(settings)
+            .with_chord(a, Action::key(0x02, 0x04))
+            .with_chord(c, Action::key(0, 0x06));
+
+        let differences = old.diff(&new);
+        assert!(differences.len() == 4);
+        assert!(differences[0].to_string() == "~ haptic: on -> off");
+        assert!(differences[1].to_string() == "~ 1R: a -> A");
+        assert!(differences[2].to_string() == "- 1M: b");
+        assert!(differences[3].to_string() == "+ 1L: c");
+        assert!(old.diff(&old).is_empty());
+    }
+
+    #[test]
+    fn test_problems() {
+        let a = ButtonState {
+            f1r: true,
+            ..Default::default()
+        };
+        let layout = Layout::new()
+            .with_chord(a, Action::key(0, 0x04))
+            .with_chord(ButtonState::default(), Action::key(0, 0x05))
+            .with_chord(a, Action::String(vec![]));
+
+        let problems = layout.problems();
+        assert!(problems.len() == 3);
+        assert!(problems[0] == "chord 2 has no buttons");
+        assert!(problems[1] == "chord 3 (1R) has the same buttons as chord 1");
+        assert!(problems[2] == "chord 3 types an empty string");
+    }
+}
@@ -0,0 +1,588 @@
+//! Read, convert and write Twiddler configs.
+//!
+//! Every format is read into a [`Layout`] and written from one. The format
+//! modules also have their own `parse` and `write` for working on a config
+//! without converting it, like [`twiddler7::parse`] and [`twiddler7::write`].
+//!
+//! Nothing is printed, anything dropped or changed on the way comes back as
+//! a list of warnings for the caller to show.
+
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+
+use binary::BinaryConfig;
+use byteorder::ReadBytesExt;
+
+pub mod binary;
+pub mod buttons;
+pub mod csv;
+pub mod dido;
+pub mod error;
+pub mod format;
+pub mod hid;
+pub mod layout;
+pub mod twiddler5;
+pub mod twiddler6;
+pub mod twiddler7;
+
+pub use error::Error;
+pub use format::{Format, ReadOptions, WriteOptions};
+pub use layout::{Layout, SettingsChanges};
+
+/// Read a v6 or v7 config and write it back byte for byte, for editing device configs
+pub fn copy_lossless<R: Read + Seek, W: Write + Seek>(
+    reader: &mut R,
+    writer: &mut W,
+    format: Format,
+) -> Result<(), Error> {
+    reader.seek(SeekFrom::Start(4))?;
+    let version = reader.read_u8()?;
+    reader.seek(SeekFrom::Start(0))?;
+
+    match (format, version) {
+        (Format::V6, 6) => {
+            let config = twiddler6::parse(reader)?;
+            twiddler6::write_lossless(&config, writer)?;
+        }
+        (Format::V7, 7) => {
+            let config = twiddler7::parse(reader)?;
+            twiddler7::write_lossless(&config, writer)?;
+        }
+        (Format::V6 | Format::V7, version) => {
+            return Err(Error::Conversion(format!(
+                "Lossless copies need the same version in and out, input is version {}",
+                version
+            )))
+        }
+        (format, _) => {
+            return Err(Error::Conversion(format!(
+                "Lossless copies only work for v6 and v7 configs, not {:?}",
+                format
+            )))
+        }
+    }
+
+    Ok(())
+}
+
+/// Read a config in any format, the format is detected from the contents
+/// unless `options.format` is set
+pub fn read<R: Read>(
+    reader: &mut R,
+    options: &ReadOptions,
+) -> Result<(Layout, Vec<String>), Error> {
+    read_with_format(reader, options).map(|(_, layout, warnings)| (layout, warnings))
+}
+
+/// Like [`read`], also returning the format the input was read as
+pub fn read_with_format<R: Read>(
+    reader: &mut R,
+    options: &ReadOptions,
+) -> Result<(Format, Layout, Vec<String>), Error> {
+    let mut bytes = vec![];
+    reader.read_to_end(&mut bytes)?;
+
+    let format = input_format(&bytes, options)?;
+    let (layout, warnings) = (format.handler().read)(&bytes, options)?;
+    Ok((format, layout, warnings))
+}
+
+fn input_format(bytes: &[u8], options: &ReadOptions) -> Result<Format, Error> {
+    match options.format {
+        Some(format) => Ok(format),
+        None => format::pick(bytes),
+    }
+}
+
+/// Change the settings in a config, returning it in the same format. v6 and v7
+/// configs keep every other byte, v5 and Dido configs are read and written again
+pub fn set_settings(
+    bytes: &[u8],
+    changes: &SettingsChanges,
+    options: &ReadOptions,
+) -> Result<(Vec<u8>, Vec<String>), Error> {
+    let format = input_format(bytes, options)?;
+    let handler = format.handler();
+
+    let mut output = Cursor::new(vec![]);
+    let mut warnings = vec![];
+    match format {
+        Format::V6 => {
+            let mut config = twiddler6::parse(&mut Cursor::new(bytes))?;
+            config.apply_settings(changes)?;
+            twiddler6::write_lossless(&config, &mut output)?;
+        }
+        Format::V7 => {
+            let mut config = twiddler7::parse(&mut Cursor::new(bytes))?;
+            config.apply_settings(changes)?;
+            twiddler7::write_lossless(&config, &mut output)?;
+        }
+        Format::Csv => {
+            return Err(Error::Conversion(
+                "CSV configs have no settings to change".to_owned(),
+            ))
+        }
+        Format::V5 | Format::Dido => {
+            let (mut layout, mut read_warnings) = (handler.read)(bytes, options)?;
+            layout.settings.apply(changes)?;
+            warnings.append(&mut read_warnings);
+            warnings.append(&mut write(
+                layout,
+                format,
+                &WriteOptions::default(),
+                &mut output,
+            )?);
+        }
+    }
+
+    Ok((output.into_inner(), warnings))
+}
+
+/// Write `layout` as `format`, returning a warning for everything it can't store
+pub fn write<W: Write>(
+    layout: Layout,
+    format: Format,
+    options: &WriteOptions,
+    writer: &mut W,
+) -> Result<Vec<String>, Error> {
+    let handler = format.handler();
+    let Some(write) = handler.write else {
+        return Err(Error::Conversion(format!(
+            "{}s can't be written",
+            handler.description
+        )));
+    };
+    let (bytes, warnings) = write(layout, options)?;
+    writer.write_all(&bytes)?;
+
+    Ok(warnings)
+}
+
+/// Check `layout` for likely mistakes, see [`Layout::problems`], and that
+/// it can be written as `format`. Returns the warnings from writing it
+pub fn validate(layout: &Layout, format: Format) -> Result<Vec<String>, Error> {
+    let problems = layout.problems();
+    if !problems.is_empty() {
+        return Err(Error::Problems(problems));
+    }
+
+    match format.handler().write {
+        Some(write) => Ok(write(layout.clone(), &WriteOptions::default())?.1),
+        None => Ok(vec![]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use buttons::MouseButton;
+    use layout::Action;
+
+    #[test]
+    fn test_twiddler6_to_layout() {
+        let mut file = std::fs::File::open("test/configs/v6/empty.cfg").unwrap();
+        let (layout, _) = read(&mut file, &ReadOptions::default()).unwrap();
+        assert!(layout.chords.len() == 8);
+        assert!(layout.settings.idle_time == Duration::from_secs(600));
+        assert!(layout.settings.mouse_speed == 0x80);
+        assert!(layout.settings.repeat_delay == Duration::from_millis(1000));
+        assert!(layout.settings.key_repeat == true);
+
+        // v6 and v7 system chords should come out the same
+        let mut file = std::fs::File::open("test/configs/v7/empty_w_system.cfg").unwrap();
+        let (expected, _) = twiddler7::to_layout(&twiddler7::parse(&mut file).unwrap());
+        assert!(layout.chords == expected.chords);
+
+        let mut file = std::fs::File::open("test/configs/v6/idle_time_10min.cfg").unwrap();
+        let (layout, _) = read(&mut file, &ReadOptions::default()).unwrap();
+        assert!(layout.settings.idle_time == Duration::from_secs(3600));
+    }
+
+    #[test]
+    fn test_layout_to_twiddler6() {
+        let mut file = std::fs::File::open("test/configs/v6/left_mouse_pos.cfg").unwrap();
+        let (layout, _) = read(&mut file, &ReadOptions::default()).unwrap();
+        let (config6, warnings) = twiddler6::from_layout(&layout).unwrap();
+        assert!(warnings.is_empty());
+
+        let mut output = std::io::Cursor::new(vec![]);
+        twiddler6::write(config6, &mut output).unwrap();
+        let expected = std::fs::read("test/configs/v6/left_mouse_pos.cfg").unwrap();
+        assert!(output.into_inner() == expected);
+    }
+
+    #[test]
+    fn test_haptic() {
+        let mut file = std::fs::File::open("test/configs/v6/empty.cfg").unwrap();
+        let (layout, _) = read(&mut file, &ReadOptions::default()).unwrap();
+        assert!(layout.settings.haptic == true);
+        let (config6, _) = twiddler6::from_layout(&layout).unwrap();
+        assert!(config6.device_flags.haptic() == true);
+
+        let mut file = std::fs::File::open("test/configs/v6/haptic_off.cfg").unwrap();
+        let (layout, _) = read(&mut file, &ReadOptions::default()).unwrap();
+        assert!(layout.settings.haptic == false);
+        let (config7, _) = twiddler7::from_layout(&layout).unwrap();
+        assert!(config7.flags.haptic() == false);
+
+        let mut file = std::fs::File::open("test/configs/v7/haptic_off.cfg").unwrap();
+        let (layout, _) = read(&mut file, &ReadOptions::default()).unwrap();
+        let mut output = std::io::Cursor::new(vec![]);
+        write(layout, Format::V6, &WriteOptions::default(), &mut output).unwrap();
+        assert!(output.get_ref()[0x13] & 1 == 0);
+    }
+
+    #[test]
+    fn test_mouse_speed_range() {
+        let mut layout = Layout::new();
+        layout.settings.mouse_speed = 0;
+        let (config7, _) = twiddler7::from_layout(&layout).unwrap();
+        assert!(config7.mouse_speed() == 1);
+
+        layout.settings.mouse_speed = 255;
+        let (config6, _) = twiddler6::from_layout(&layout).unwrap();
+        assert!(config6.mouse_speed() == 254);
+
+        // v5 keeps its mouse acceleration as is, v7 clamps it when it's converted
+        let mut file = std::fs::File::open("configs/backspice2_v5.cfg").unwrap();
+        let (layout, warnings) = read(&mut file, &ReadOptions::default()).unwrap();
+        assert!(warnings.is_empty());
+        assert!(layout.settings.mouse_speed == 255);
+        let (config7, warnings) = twiddler7::from_layout(&layout).unwrap();
+        assert!(config7.mouse_speed() == 254);
+        assert!(
+            warnings.contains(&"mouse speed 255 is outside the range 1-254, using 254".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_layout_to_twiddler5() {
+        let mut file = std::fs::File::open("configs/backspice2_v5.cfg").unwrap();
+        let (layout, _) = read(&mut file, &ReadOptions::default()).unwrap();
+        let (config5, warnings) = twiddler5::from_layout(&layout);
+        assert!(warnings.is_empty());
+
+        let mut output = std::io::Cursor::new(vec![]);
+        twiddler5::write(config5, &mut output).unwrap();
+        output.set_position(0);
+        let config5 = twiddler5::parse(&mut output).unwrap();
+        let original = std::fs::read("configs/backspice2_v5.cfg").unwrap();
+        assert!(*output.get_ref() == original);
+        // the mouse buttons come back as the header fields instead of chords
+        assert!(config5.chords.len() == layout.chords.len() - 3);
+
+        // system chords use T0 and aren't supported on v5
+        let mut file = std::fs::File::open("test/configs/v7/empty_w_system.cfg").unwrap();
+        let (layout, _) = twiddler7::to_layout(&twiddler7::parse(&mut file).unwrap());
+        let (config5, warnings) = twiddler5::from_layout(&layout);
+        assert!(config5.chords.is_empty());
+        assert!(warnings.len() == 8);
+    }
+
+    #[test]
+    fn test_csv_to_layout() {
+        let mut file = std::fs::File::open("configs/twiddler_cfg_CoolHand.csv").unwrap();
+        let (layout, _) = read(&mut file, &ReadOptions::default()).unwrap();
+        assert!(layout.chords.len() == 103);
+
+        // "","1R","i"
+        assert!(layout.chords[0].buttons.f1r == true);
+        assert!(layout.chords[0].buttons.t1 == false);
+        assert!(
+            layout.chords[0].action
+                == Action::Key {
+                    modifier: 0,
+                    key_code: 0x0C,
+                }
+        );
+
+        // "1","1R","<R-Ctrl>p</R-Ctrl>"
+        assert!(layout.chords[1].buttons.t1 == true);
+        assert!(
+            layout.chords[1].action
+                == Action::Key {
+                    modifier: 0x10,
+                    key_code: 0x13,
+                }
+        );
+
+        // strings like "you " type each key in turn
+        assert!(layout
+            .chords
+            .iter()
+            .any(|c| matches!(&c.action, Action::String(keys) if keys.len() == 4)));
+    }
+
+    #[test]
+    fn test_twiddler5_to_layout() {
+        let mut file = std::fs::File::open("configs/backspice2_v5.cfg").unwrap();
+        let (layout, _) = read(&mut file, &ReadOptions::default()).unwrap();
+        let settings = &layout.settings;
+        assert!(settings.idle_time == Duration::from_secs(3600));
+        assert!(settings.mouse_speed == 255);
+        assert!(settings.repeat_delay == Duration::from_millis(1000));
+        assert!(settings.key_repeat == true);
+        assert!(settings.direct == false);
+        assert!(settings.sticky_num == false);
+        assert!(settings.sticky_shift == false);
+
+        // normal clicks on the mouse buttons become F0 chords
+        let chord = layout.chords.iter().rev().nth(2).unwrap();
+        assert!(MouseButton::from_button_state(&chord.buttons) == Some(MouseButton::Left));
+        assert!(chord.action == Action::Mouse(layout::Mouse::click(MouseButton::Left)));
+
+        // and other actions keep theirs
+        let mut config5 = twiddler5::Config::new();
+        config5.set_mouse_action(
+            MouseButton::Right,
+            Some(twiddler5::ChordMapping::KeyMapping(0, 0x2B)),
+        );
+        assert!(config5.mouse_right_click == 0x2B00);
+        let (layout, _) = twiddler5::to_layout(&config5).unwrap();
+        assert!(
+            layout.chords.last().unwrap().action
+                == Action::Key {
+                    modifier: 0,
+                    key_code: 0x2B,
+                }
+        );
+        let (config5, warnings) = twiddler5::from_layout(&layout);
+        assert!(warnings.is_empty());
+        assert!(config5.chords.is_empty());
+        assert!(config5.mouse_right_click == 0x2B00);
+        assert!(config5.mouse_left_click == 0);
+    }
+
+    #[test]
+    fn test_dido_to_layout() {
+        let mut file = std::fs::File::open("configs/CoolHand.txt").unwrap();
+        let (layout, _) = read(&mut file, &ReadOptions::default()).unwrap();
+        let settings = &layout.settings;
+        assert!(settings.idle_time == Duration::from_secs(900));
+        assert!(settings.mouse_speed == 10);
+        assert!(settings.repeat_delay == Duration::from_millis(1000));
+        assert!(settings.key_repeat == true);
+        assert!(settings.sticky_num == false);
+        assert!(settings.direct == false);
+
+        // mouse_mid=false is followed by Caps Lock
+        let chord = layout
+            .chords
+            .iter()
+            .find(|c| MouseButton::from_button_state(&c.buttons) == Some(MouseButton::Middle))
+            .unwrap();
+        assert!(
+            chord.action
+                == Action::Key {
+                    modifier: 0,
+                    key_code: 57,
+                }
+        );
+
+        let mut warnings = vec![];
+        assert!(layout::clamp_setting("mouse_speed", 300, 1, 254, &mut warnings) == 254);
+        assert!(layout::clamp_setting("mouse_speed", 0, 1, 254, &mut warnings) == 1);
+        assert!(layout::clamp_setting("mouse_speed", 80, 1, 254, &mut warnings) == 80);
+        assert!(warnings.len() == 2);
+    }
+
+    #[test]
+    fn test_lossless_round_trip() {
+        for (dir, format) in [
+            ("test/configs/v6", Format::V6),
+            ("test/configs/v7", Format::V7),
+        ] {
+            for entry in std::fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                let original = std::fs::read(&path).unwrap();
+
+                let mut output = std::io::Cursor::new(vec![]);
+                copy_lossless(&mut std::io::Cursor::new(&original), &mut output, format).unwrap();
+                assert!(output.into_inner() == original, "{:?} changed", path);
+            }
+        }
+
+        // unknown bytes survive too
+        let mut original = std::fs::read("test/configs/v7/default.cfg").unwrap();
+        original[0x07] = 0xAA;
+        original[0x20] = 0x55;
+        original.extend([1, 2, 3]);
+        let mut output = std::io::Cursor::new(vec![]);
+        copy_lossless(
+            &mut std::io::Cursor::new(&original),
+            &mut output,
+            Format::V7,
+        )
+        .unwrap();
+        assert!(output.into_inner() == original);
+
+        let mut file = std::fs::File::open("test/configs/v6/empty.cfg").unwrap();
+        let mut output = std::io::Cursor::new(vec![]);
+        assert!(copy_lossless(&mut file, &mut output, Format::V7).is_err());
+        assert!(copy_lossless(&mut file, &mut output, Format::Csv).is_err());
+    }
+
+    #[test]
+    fn test_set_settings() {
+        let original = std::fs::read("test/configs/v7/empty_w_system.cfg").unwrap();
+        let changes = SettingsChanges {
+            left_mouse_pos: Some(true),
+            ..Default::default()
+        };
+        let (output, _) = set_settings(&original, &changes, &ReadOptions::default()).unwrap();
+        assert!(output == std::fs::read("test/configs/v7/left_mouse_pos.cfg").unwrap());
+
+        let original = std::fs::read("test/configs/v6/empty.cfg").unwrap();
+        let changes = SettingsChanges {
+            idle_time: Some(Duration::from_secs(60)),
+            haptic: Some(false),
+            ..Default::default()
+        };
+        let (output, _) = set_settings(&original, &changes, &ReadOptions::default()).unwrap();
+        let config = twiddler6::parse(&mut std::io::Cursor::new(&output)).unwrap();
+        assert!(config.idle_time == 60);
+        assert!(config.device_flags.haptic() == false);
+        assert!(output[0x28..] == original[0x28..]);
+
+        let original = std::fs::read("configs/twiddler_cfg_CoolHand.csv").unwrap();
+        assert!(set_settings(&original, &changes, &ReadOptions::default()).is_err());
+    }
+
+    #[test]
+    fn test_errors() {
+        let err = read(&mut std::io::Cursor::new(vec![]), &ReadOptions::default()).unwrap_err();
+        assert!(matches!(err, Error::UnknownFormat(_)));
+
+        // a v7 header with a chord count but no chords
+        let mut original = std::fs::read("test/configs/v7/empty.cfg").unwrap();
+        original[8] = 1;
+        let err = read(&mut std::io::Cursor::new(original), &ReadOptions::default()).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Parse {
+                format: "v7",
+                location: error::Location::Offset(0x80),
+                ..
+            }
+        ));
+
+        let text = "# --- end of options\nsleep=soon\n";
+        let options = ReadOptions {
+            name: "layout.txt".to_owned(),
+            dido_mode: dido::Mode::Strict,
+            ..Default::default()
+        };
+        let err = read(&mut std::io::Cursor::new(text), &options).unwrap_err();
+        assert!(
+            err.to_string()
+                == "Found 1 problem in the config\n  layout.txt:2:7: expected a number, found \"soon\""
+        );
+
+        let buttons = buttons::parse_notation(String::new(), "1R".to_owned());
+        let layout = Layout::new().with_chord(buttons, Action::key(0, 0x04));
+        assert!(validate(&layout, Format::V7).is_ok());
+        let err = validate(
+            &layout.with_chord(buttons, Action::key(0, 0x05)),
+            Format::V7,
+        )
+        .unwrap_err();
+        assert!(
+            err.to_string()
+                == "Found 1 problem in the layout\n  chord 2 (1R) has the same buttons as chord 1"
+        );
+    }
+
+    #[test]
+    fn test_shared_command_lists() {
+        let chord = |fingers: &str| buttons::parse_notation(String::new(), fingers.to_owned());
+        let string = Action::keys(&[(0, 0x04), (0, 0x05)]);
+        let unique = Layout::new()
+            .with_chord(chord("1L"), string.clone())
+            .with_chord(chord("1M"), Action::keys(&[(0, 0x06), (0, 0x07)]));
+        let duplicate = Layout::new()
+            .with_chord(chord("1L"), string.clone())
+            .with_chord(chord("1M"), string);
+
+        for format in [Format::V6, Format::V7] {
+            let mut unique_output = vec![];
+            let warnings = write(
+                unique.clone(),
+                format,
+                &WriteOptions::default(),
+                &mut unique_output,
+            )
+            .unwrap();
+            assert!(warnings.is_empty());
+
+            let mut output = vec![];
+            let warnings = write(
+                duplicate.clone(),
+                format,
+                &WriteOptions::default(),
+                &mut output,
+            )
+            .unwrap();
+            assert!(warnings == vec!["shared 1 identical command lists, saving 12 bytes"]);
+            assert!(output.len() == unique_output.len() - 12);
+        }
+    }
+
+    #[test]
+    fn test_builders() {
+        let buttons = buttons::parse_notation("1".to_owned(), "1R".to_owned());
+        let config = twiddler7::Config::new()
+            .with_flags(twiddler7::ConfigFlags::new().with_sticky_shift(true))
+            .with_idle_time(900)
+            .with_mouse_speed(80)
+            .with_chord(twiddler7::Chord::new(
+                buttons,
+                twiddler7::Command::keyboard(0, 0x04),
+            ))
+            .with_list_chord(
+                buttons::parse_notation(String::new(), "2M".to_owned()),
+                vec![
+                    twiddler7::Command::keyboard(0x02, 0x0B),
+                    twiddler7::Command::keyboard(0, 0x0C),
+                ],
+            );
+        assert!(config.version() == 7);
+
+        let mut output = std::io::Cursor::new(vec![]);
+        twiddler7::write(config, &mut output).unwrap();
+        output.set_position(0);
+        let config = twiddler7::parse(&mut output).unwrap();
+        assert!(config.flags.sticky_shift() == true);
+        assert!(config.chords[0].button_state() == buttons);
+
+        let (layout, _) = twiddler7::to_layout(&config);
+        assert!(layout.settings.idle_time == Duration::from_secs(900));
+        assert!(layout.settings.mouse_speed == 80);
+        assert!(layout.chords[1].action == Action::keys(&[(0x02, 0x0B), (0, 0x0C)]));
+
+        // and the same through the layout builder, in every format
+        let expected = Layout::new().with_chord(buttons, Action::key(0, 0x04));
+        for format in [
+            Format::V5,
+            Format::V6,
+            Format::V7,
+            Format::Csv,
+            Format::Dido,
+        ] {
+            let mut output = vec![];
+            write(
+                expected.clone(),
+                format,
+                &WriteOptions::default(),
+                &mut output,
+            )
+            .unwrap();
+            let options = ReadOptions {
+                format: Some(format),
+                ..Default::default()
+            };
+            let (layout, _) = read(&mut output.as_slice(), &options).unwrap();
+            assert!(layout.chords[0] == expected.chords[0], "{:?}", format);
+        }
+    }
+}
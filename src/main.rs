@@ -1,29 +1,159 @@
-#[macro_use]
-extern crate simple_error;
+use std::{io::Read, path::PathBuf, time::Duration};
 
-use std::io::{Read, Seek, SeekFrom};
+use clap::{builder::BoolishValueParser, Args, Parser, Subcommand, ValueEnum};
+use clio::{Input, Output};
+use twiddler_cfg::{
+    buttons::{GridStyle, MouseButton},
+    dido,
+    layout::{self, Action},
+    Error, Format, Layout, ReadOptions, SettingsChanges, WriteOptions,
+};
 
-use byteorder::ReadBytesExt;
+#[derive(Parser)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+struct Opt {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// `twiddler_cfg <INPUT> <OUTPUT>` is the same as `twiddler_cfg convert <INPUT> <OUTPUT>`
+    #[command(flatten)]
+    convert: ConvertArgs,
+}
 
-mod buttons;
-mod csv;
-mod dido;
-mod hid;
-mod twiddler5;
-mod twiddler6;
-mod twiddler7;
+#[derive(Subcommand)]
+enum Command {
+    /// Convert a config to another format
+    Convert(ConvertArgs),
+    /// Show a config's format, settings and how many chords it has
+    Info(InputArgs),
+    /// Show a table of every chord with its buttons and output
+    Dump(DumpArgs),
+    /// Check a config for problems, reading Dido configs strictly
+    Validate(ValidateArgs),
+    /// Show the settings and chords that differ between two configs
+    Diff(DiffArgs),
+    /// Change settings in a config file in place
+    Set(SetArgs),
+}
 
-use clap::Parser;
-use clio::*;
+/// How to read the input, shared by every subcommand
+#[derive(Args)]
+struct ReadArgs {
+    /// Config format to read, detected from the contents when left out
+    #[clap(long, short, value_enum)]
+    input_format: Option<FormatArg>,
 
-#[derive(Parser)]
-#[command(version, about, long_about = None)]
-struct Opt {
+    /// Fail on any problem in a Dido input instead of warning and skipping the line
+    #[clap(long)]
+    strict: bool,
+
+    /// Print how the input format was detected
+    #[clap(long, short)]
+    verbose: bool,
+}
+
+impl ReadArgs {
+    fn read(&self, input: &mut Input) -> Result<(Format, Layout), Error> {
+        let options = ReadOptions {
+            name: input.path().path().display().to_string(),
+            format: self.input_format.map(Format::from),
+            dido_mode: if self.strict {
+                dido::Mode::Strict
+            } else {
+                dido::Mode::Lenient
+            },
+        };
+        read_input(input, &options, self.verbose)
+    }
+}
+
+#[derive(Args)]
+struct InputArgs {
     #[clap(value_parser)]
     input: Input,
 
-    #[clap(value_parser)]
-    output: Output,
+    #[command(flatten)]
+    read: ReadArgs,
+}
+
+#[derive(Args)]
+struct DumpArgs {
+    #[command(flatten)]
+    input: InputArgs,
+
+    /// Draw each chord's buttons as they sit on the device instead of a table
+    #[clap(
+        long,
+        value_enum,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "ascii"
+    )]
+    grid: Option<GridArg>,
+}
+
+/// `Format` for the command line, so the library doesn't need clap
+#[derive(Copy, Clone, ValueEnum)]
+enum FormatArg {
+    V5,
+    V6,
+    V7,
+    Csv,
+    Dido,
+}
+
+impl From<FormatArg> for Format {
+    fn from(arg: FormatArg) -> Self {
+        match arg {
+            FormatArg::V5 => Format::V5,
+            FormatArg::V6 => Format::V6,
+            FormatArg::V7 => Format::V7,
+            FormatArg::Csv => Format::Csv,
+            FormatArg::Dido => Format::Dido,
+        }
+    }
+}
+
+/// `GridStyle` for the command line
+#[derive(Copy, Clone, ValueEnum)]
+enum GridArg {
+    /// `[X]` for pressed buttons and `[ ]` for the rest
+    Ascii,
+    /// Filled and empty boxes
+    Unicode,
+}
+
+impl From<GridArg> for GridStyle {
+    fn from(arg: GridArg) -> Self {
+        match arg {
+            GridArg::Ascii => GridStyle::Ascii,
+            GridArg::Unicode => GridStyle::Unicode,
+        }
+    }
+}
+
+#[derive(Args)]
+struct ConvertArgs {
+    // only optional without a subcommand, clap can't leave out a flattened group
+    #[clap(value_parser, required = true)]
+    input: Option<Input>,
+
+    #[clap(value_parser, required = true)]
+    output: Option<Output>,
+
+    #[command(flatten)]
+    read: ReadArgs,
+
+    /// Config format to write, defaults to the output file's extension
+    /// or name (e.g. layout.csv, layout.txt or layout_v6.cfg) and falls back to v7
+    #[clap(long, short, value_enum)]
+    output_format: Option<FormatArg>,
 
     /// Generate upper case versions of chords with shift,
     /// 1 2 3 or 4 for the thumb key that should act as shift
@@ -33,189 +163,377 @@ struct Opt {
     /// Skip adding the default system chords to the output
     #[clap(long, short)]
     skip_system_chords: bool,
+
+    /// Copy a v6 or v7 config to the same version without changing any bytes,
+    /// nothing is generated and offsets are kept as read
+    #[clap(long)]
+    lossless: bool,
+
+    #[command(flatten, next_help_heading = "Settings")]
+    settings: SettingsArgs,
+}
+
+#[derive(Args)]
+struct ValidateArgs {
+    #[clap(value_parser)]
+    input: Input,
+
+    // Dido configs are always read strictly, --strict is accepted but changes nothing
+    #[command(flatten)]
+    read: ReadArgs,
+
+    /// Also check the config can be written as this format,
+    /// defaults to the format it was read as
+    #[clap(long, short, value_enum)]
+    output_format: Option<FormatArg>,
+}
+
+#[derive(Args)]
+struct DiffArgs {
+    #[clap(value_parser)]
+    old: Input,
+
+    #[clap(value_parser)]
+    new: Input,
+
+    #[command(flatten)]
+    read: ReadArgs,
+}
+
+#[derive(Args)]
+struct SetArgs {
+    /// v6 and v7 configs keep every other byte, v5 and Dido configs are written again
+    file: PathBuf,
+
+    /// Config format to read, detected from the contents when left out
+    #[clap(long, short, value_enum)]
+    input_format: Option<FormatArg>,
+
+    /// Print how the input format was detected
+    #[clap(long, short)]
+    verbose: bool,
+
+    #[command(flatten, next_help_heading = "Settings")]
+    settings: SettingsArgs,
+}
+
+/// Device settings to change, see `SettingsChanges`
+#[derive(Args)]
+struct SettingsArgs {
+    /// Repeat a chord while it's held
+    #[arg(long, value_name = "on|off", value_parser = BoolishValueParser::new(), hide_possible_values = true)]
+    key_repeat: Option<bool>,
+
+    /// Vibrate when a chord is typed
+    #[arg(long, value_name = "on|off", value_parser = BoolishValueParser::new(), hide_possible_values = true)]
+    haptic: Option<bool>,
+
+    #[arg(long, value_name = "on|off", value_parser = BoolishValueParser::new(), hide_possible_values = true)]
+    direct: Option<bool>,
+
+    #[arg(long, value_name = "on|off", value_parser = BoolishValueParser::new(), hide_possible_values = true)]
+    sticky_num: Option<bool>,
+
+    #[arg(long, value_name = "on|off", value_parser = BoolishValueParser::new(), hide_possible_values = true)]
+    sticky_alt: Option<bool>,
+
+    #[arg(long, value_name = "on|off", value_parser = BoolishValueParser::new(), hide_possible_values = true)]
+    sticky_ctrl: Option<bool>,
+
+    #[arg(long, value_name = "on|off", value_parser = BoolishValueParser::new(), hide_possible_values = true)]
+    sticky_shift: Option<bool>,
+
+    /// Mouse buttons on F0L instead of F0R
+    #[arg(long, value_name = "on|off", value_parser = BoolishValueParser::new(), hide_possible_values = true)]
+    left_mouse_pos: Option<bool>,
+
+    /// How long the device waits before sleeping, like 90s or 10m, up to 65535s
+    #[arg(long, value_name = "DURATION", value_parser = duration_arg)]
+    idle_time: Option<Duration>,
+
+    /// Mouse speed as shown on the device, 1 is slowest and 254 fastest
+    #[arg(
+        long = "mouse-sensitivity",
+        alias = "mouse-speed",
+        value_name = "1-254"
+    )]
+    mouse_speed: Option<u8>,
+
+    /// How long a chord is held before it repeats, 10ms to 2500ms in 10ms steps
+    #[arg(long, value_name = "DURATION", value_parser = duration_arg)]
+    repeat_delay: Option<Duration>,
+}
+
+impl From<&SettingsArgs> for SettingsChanges {
+    fn from(args: &SettingsArgs) -> Self {
+        SettingsChanges {
+            key_repeat: args.key_repeat,
+            haptic: args.haptic,
+            direct: args.direct,
+            sticky_num: args.sticky_num,
+            sticky_alt: args.sticky_alt,
+            sticky_ctrl: args.sticky_ctrl,
+            sticky_shift: args.sticky_shift,
+            left_mouse_pos: args.left_mouse_pos,
+            idle_time: args.idle_time,
+            mouse_speed: args.mouse_speed,
+            repeat_delay: args.repeat_delay,
+        }
+    }
+}
+
+/// `parse_duration` for clap, which puts its own "invalid value" in front
+fn duration_arg(text: &str) -> Result<Duration, String> {
+    layout::parse_duration(text).map_err(|e| match e {
+        Error::InvalidValue(message) => message,
+        e => e.to_string(),
+    })
+}
+
+/// Read a config, printing its warnings and, with `verbose`, how its format was picked
+fn read_input<R: Read>(
+    reader: &mut R,
+    options: &ReadOptions,
+    verbose: bool,
+) -> Result<(Format, Layout), Error> {
+    let mut bytes = vec![];
+    reader.read_to_end(&mut bytes)?;
+    if verbose {
+        print_detection(&bytes, options.format);
+    }
+
+    let (format, layout, warnings) =
+        twiddler_cfg::read_with_format(&mut bytes.as_slice(), options)?;
+    print_warnings(&warnings);
+    Ok((format, layout))
+}
+
+fn print_detection(bytes: &[u8], input_format: Option<Format>) {
+    if let Some(format) = input_format {
+        eprintln!("Input format {} from --input-format", format.handler().name);
+        return;
+    }
+
+    for (format, detection) in twiddler_cfg::format::detect(bytes) {
+        eprintln!(
+            "{:>5}: {:>3}% {}",
+            format.handler().name,
+            detection.confidence,
+            detection.reason
+        );
+    }
+}
+
+/// Warnings go to stderr so they don't mix with what a subcommand shows
+fn print_warnings(warnings: &[String]) {
+    for warning in warnings {
+        eprintln!("Warning: {}", warning);
+    }
 }
 
 fn main() {
-    let mut opt = Opt::parse();
-
-    match load_config(&mut opt.input) {
-        Ok(config) => {
-            let res = twiddler7::write(
-                config,
-                &mut opt.output,
-                opt.generate_caps,
-                !opt.skip_system_chords,
-            );
-            match res {
-                Ok(_) => {
-                    println!("Done");
-                }
-                Err(e) => {
-                    println!("Failed to write output config{:?}", e);
-                }
-            }
+    let opt = Opt::parse();
+
+    let result = match opt.command {
+        Some(Command::Convert(mut args)) => convert(&mut args),
+        Some(Command::Info(mut args)) => info(&mut args),
+        Some(Command::Dump(mut args)) => dump(&mut args),
+        Some(Command::Validate(args)) => validate(args),
+        Some(Command::Diff(args)) => diff(args),
+        Some(Command::Set(args)) => set(args),
+        None => {
+            let mut args = opt.convert;
+            convert(&mut args)
         }
-        Err(e) => {
-            println!("Failed to load input config{:?}", e);
+    };
+
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn convert(args: &mut ConvertArgs) -> Result<(), Error> {
+    let (Some(input), Some(output)) = (&mut args.input, &mut args.output) else {
+        return Err(Error::InvalidValue(
+            "expected a subcommand or <INPUT> <OUTPUT>, see --help".to_owned(),
+        ));
+    };
+
+    let output_format = args
+        .output_format
+        .map(Format::from)
+        .or_else(|| Format::from_path(output.path().path()))
+        .unwrap_or(Format::V7);
+    let settings = SettingsChanges::from(&args.settings);
+
+    if args.lossless {
+        if !settings.is_empty() {
+            return Err(Error::Conversion(
+                "lossless copies can't change settings, use `set` on the copy instead".to_owned(),
+            ));
         }
+        twiddler_cfg::copy_lossless(input, output, output_format)?;
+        println!("Done");
+        return Ok(());
     }
+
+    let (input_format, mut layout) = args.read.read(input)?;
+    println!("Read input as {}", input_format.handler().description);
+    layout.settings.apply(&settings)?;
+
+    let write_options = WriteOptions {
+        generate_caps: args.generate_caps,
+        system_chords: !args.skip_system_chords,
+    };
+    println!("Writing output as {}", output_format.handler().description);
+    let warnings = twiddler_cfg::write(layout, output_format, &write_options, output)?;
+    print_warnings(&warnings);
+    println!("Done");
+
+    Ok(())
 }
 
-fn load_config<R: Read + Seek>(
-    reader: &mut R,
-) -> std::result::Result<twiddler7::Config, Box<dyn std::error::Error>> {
-    reader.seek(SeekFrom::Start(0));
-    if reader.read_u8().unwrap() == 0x05 {
-        println!("Reading input as Twiddler 5 config");
-        reader.seek(SeekFrom::Start(0));
-        let config = twiddler5::parse(reader)?;
-        return Ok(twiddler5_to_twiddler7(&config));
-    }
-
-    reader.seek(SeekFrom::Start(4));
-    if reader.read_u8().unwrap() == 0x06 {
-        println!("Twiddler 6 config detected");
-        bail!("Twiddler 6 config not supported yet");
-    }
-
-    reader.seek(SeekFrom::Start(4));
-    if reader.read_u8().unwrap() == 0x07 {
-        println!("Twiddler 7 config detected");
-        println!("Running through twiddler 7 parser to ensure it's valid");
-        reader.seek(SeekFrom::Start(0));
-        let conf = twiddler7::parse(reader)?;
-        return Ok(conf);
-    }
-
-    reader.seek(SeekFrom::Start(0));
-    if reader.read_u8().unwrap() == '#' as u8 {
-        println!("Starts with a #, assuming Dido config");
-        let res = dido::parse(reader);
-        match res {
-            Ok(config) => {
-                let config7 = dido_to_twiddler7(config);
-                return Ok(config7);
-            }
-            Err(e) => {
-                bail!("Failed parsing dido config {:?}", e);
-            }
+fn info(args: &mut InputArgs) -> Result<(), Error> {
+    let (format, layout) = args.read.read(&mut args.input)?;
+
+    let handler = format.handler();
+    match format {
+        Format::V5 => println!("Format: {} (version 5)", handler.description),
+        Format::V6 => println!("Format: {} (version 6)", handler.description),
+        Format::V7 => println!("Format: {} (version 7)", handler.description),
+        Format::Csv | Format::Dido => println!("Format: {}", handler.description),
+    }
+
+    // v5 and Dido keep what the mouse buttons do in the header, they're read as F0 chords
+    let mouse_buttons = match format {
+        Format::V5 | Format::Dido => layout
+            .chords
+            .iter()
+            .filter(|chord| MouseButton::from_button_state(&chord.buttons).is_some())
+            .count(),
+        Format::V6 | Format::V7 | Format::Csv => 0,
+    };
+    let strings = layout
+        .chords
+        .iter()
+        .filter(|chord| matches!(chord.action, Action::String(_)))
+        .count();
+    println!("Chords: {}", layout.chords.len() - mouse_buttons);
+    if mouse_buttons > 0 {
+        println!("Mouse buttons: {}", mouse_buttons);
+    }
+    println!("Strings/lists: {}", strings);
+
+    let flags = layout
+        .settings
+        .flags()
+        .into_iter()
+        .filter(|(_, on)| *on)
+        .map(|(name, _)| name)
+        .collect::<Vec<_>>();
+    println!("Flags: {}", flags.join(", "));
+
+    println!("Settings:");
+    for (name, value) in layout.settings.values() {
+        println!("  {}: {}", name, value);
+    }
+
+    Ok(())
+}
+
+fn dump(args: &mut DumpArgs) -> Result<(), Error> {
+    let input = &mut args.input;
+    let (_, layout) = input.read.read(&mut input.input)?;
+
+    if let Some(style) = args.grid {
+        for (i, chord) in layout.chords.iter().enumerate() {
+            println!("Chord {}\n{}\n", i + 1, chord.render(style.into()));
         }
+        return Ok(());
     }
 
-    println!("Reading input as csv config");
-    reader.seek(SeekFrom::Start(0));
-    let res = csv::parse(reader);
-    bail!("CSV conversion implementation incomplete");
-}
-
-fn dido_to_twiddler7(config: dido::Config) -> twiddler7::Config {
-    let mut config7 = twiddler7::Config::new();
-    config.chords.iter().for_each(|c| {
-        let command = match &c.output {
-            dido::ChordOutput::HidCode(key_code) => {
-                let key_code = key_code.parse().unwrap();
-                twiddler7::Command {
-                    command_type: twiddler7::CommandType::Keyboard,
-                    data: twiddler7::CommandData::Keyboard(twiddler7::HidCommand {
-                        modifier: c.modifiers,
-                        key_code,
-                    }),
-                }
-            }
-            dido::ChordOutput::StringIndex(index) => {
-                let index = index.parse::<usize>().unwrap();
-                let command = twiddler7::Command {
-                    command_type: twiddler7::CommandType::ListOfCommands,
-                    data: twiddler7::CommandData::ListOfCommands(0),
-                };
-
-                let out_string_hids = &config.strings[index];
-
-                let mut command_list = vec![];
-
-                for hids in out_string_hids {
-                    command_list.push(twiddler7::Command {
-                        command_type: twiddler7::CommandType::Keyboard,
-                        data: twiddler7::CommandData::Keyboard(twiddler7::HidCommand {
-                            key_code: hids.0,
-                            modifier: hids.1,
-                        }),
-                    });
-                }
-
-                config7
-                    .command_lists
-                    .push(twiddler7::CommandList(command_list));
-
-                command
-            }
-        };
+    let rows = layout
+        .chords
+        .iter()
+        .enumerate()
+        .map(|(i, chord)| {
+            (
+                (i + 1).to_string(),
+                chord.buttons.to_string(),
+                chord.action.to_string(),
+            )
+        })
+        .collect::<Vec<_>>();
+    let number_width = rows.iter().map(|row| row.0.len()).max().unwrap_or(0).max(1);
+    let buttons_width = rows.iter().map(|row| row.1.len()).max().unwrap_or(0).max(7);
+
+    println!(
+        "{:>number_width$}  {:<buttons_width$}  Output",
+        "#", "Buttons"
+    );
+    for (number, buttons, output) in rows {
+        println!(
+            "{:>number_width$}  {:<buttons_width$}  {}",
+            number, buttons, output
+        );
+    }
+
+    Ok(())
+}
+
+fn validate(mut args: ValidateArgs) -> Result<(), Error> {
+    args.read.strict = true;
+    let (format, layout) = args.read.read(&mut args.input)?;
 
-        config7.chords.push(twiddler7::Chord {
-            buttons: twiddler7::ButtonData::from(&c.buttons),
-            command,
-        });
-    });
+    let warnings =
+        twiddler_cfg::validate(&layout, args.output_format.map_or(format, Format::from))?;
+    print_warnings(&warnings);
+    println!("No problems found in {} chords", layout.chords.len());
 
-    config7
+    Ok(())
 }
 
-fn twiddler5_to_twiddler7(config: &twiddler5::Config) -> twiddler7::Config {
-    let mut config7 = twiddler7::Config::new();
-    config.chords.iter().for_each(|c| {
-        let button_state = c.button_state();
+fn diff(mut args: DiffArgs) -> Result<(), Error> {
+    let mut layouts = vec![];
+    for input in [&mut args.old, &mut args.new] {
+        let (_, layout) = args.read.read(input)?;
+        layouts.push(layout);
+    }
 
-        let command = match c.mapping {
-            twiddler5::ChordMapping::KeyMapping(modifier, key_code) => twiddler7::Command {
-                command_type: twiddler7::CommandType::Keyboard,
-                data: twiddler7::CommandData::Keyboard(twiddler7::HidCommand {
-                    modifier,
-                    key_code,
-                }),
-            },
-            twiddler5::ChordMapping::StringMapping(_, index) => {
-                let command = twiddler7::Command {
-                    command_type: twiddler7::CommandType::ListOfCommands,
-                    data: twiddler7::CommandData::ListOfCommands(0),
-                };
-
-                let string_pos = config.string_locations[index as usize];
-                let contents = config
-                    .string_contents
-                    .iter()
-                    .find(|sc| sc.pos == string_pos.into())
-                    .unwrap();
-
-                let mut command_list = vec![];
-
-                for c in &contents.keys {
-                    match c {
-                        twiddler5::ChordMapping::KeyMapping(modifier, key_code) => {
-                            command_list.push(twiddler7::Command {
-                                command_type: twiddler7::CommandType::Keyboard,
-                                data: twiddler7::CommandData::Keyboard(twiddler7::HidCommand {
-                                    modifier: *modifier,
-                                    key_code: *key_code,
-                                }),
-                            });
-                        }
-                        _ => {}
-                    }
-                }
-
-                config7
-                    .command_lists
-                    .push(twiddler7::CommandList(command_list));
-
-                command
-            }
-        };
+    let differences = layouts[0].diff(&layouts[1]);
+    if differences.is_empty() {
+        println!("No differences");
+    }
+    for difference in differences {
+        println!("{}", difference);
+    }
+
+    Ok(())
+}
 
-        config7.chords.push(twiddler7::Chord {
-            buttons: button_state.into(),
-            command,
-        });
-    });
+fn set(args: SetArgs) -> Result<(), Error> {
+    let settings = SettingsChanges::from(&args.settings);
+    if settings.is_empty() {
+        return Err(Error::InvalidValue(
+            "no settings to change, see `set --help`".to_owned(),
+        ));
+    }
+
+    // Dido lines that can't be read would be lost when it's written again
+    let options = ReadOptions {
+        name: args.file.display().to_string(),
+        format: args.input_format.map(Format::from),
+        dido_mode: dido::Mode::Strict,
+    };
+    let bytes = std::fs::read(&args.file)?;
+    if args.verbose {
+        print_detection(&bytes, options.format);
+    }
+    let (bytes, warnings) = twiddler_cfg::set_settings(&bytes, &settings, &options)?;
+    print_warnings(&warnings);
+    std::fs::write(&args.file, bytes)?;
+    println!("Done");
 
-    config7
+    Ok(())
 }
@@ -1,60 +1,167 @@
-use std::io::{Read, Seek};
+use std::io::{Read, Seek, Write};
 
-use binrw::{BinRead, PosValue};
+use binrw::{binrw, BinRead, BinWrite, PosValue};
 use modular_bitfield::prelude::*;
 
-use crate::buttons::ButtonState;
+use crate::{
+    buttons::{ButtonState, MouseButton},
+    error::{Error, Location},
+    layout::{self, clamp_setting, Action, Layout, Settings},
+};
 
-#[derive(BinRead)]
-#[br(little)]
+const HEADER_SIZE: u32 = 16;
+
+#[bitfield]
+#[derive(BinRead, BinWrite, Debug, Copy, Clone, Default)]
+#[br(map = Self::from_bytes)]
+pub struct OptionsA {
+    pub key_repeat: bool,
+    pub direct_key: bool,
+    pub left_click: bool, // joystick click, right click when off
+    pub bluetooth_off: bool,
+    pub sticky_num: bool,
+    pub sticky_shift: bool,
+    pub unknown: B2,
+}
+
+#[binrw]
+#[brw(little)]
 #[derive(Debug)]
 pub struct Config {
     version: u8,
-    options_a: u8,
-    number_of_chords: u16,
-    sleep_timeout: u16,
-    mouse_left_click: u16,   // todo: these can also be actions and probably
-    mouse_middle_click: u16, // invoke string actions, make sure to
-    mouse_right_click: u16,  // add these to the count if so
-    mouse_accel_factor: u8,
-    key_repeat_delay: u8,
-    options_b: u8,
-    options_c: u8,
+    pub options_a: OptionsA,
+    pub number_of_chords: u16,
+    pub sleep_timeout: u16,
+    pub mouse_left_click: u16, // 0 for a normal click, otherwise a chord mapping,
+    pub mouse_middle_click: u16, // see mouse_action()
+    pub mouse_right_click: u16,
+    pub mouse_accel_factor: u8,
+    pub key_repeat_delay: u8,
+    pub options_b: u8, // not documented, kept as is
+    pub options_c: u8,
 
     #[br(count = number_of_chords)]
     pub chords: Vec<Chord>,
 
-    #[br(calc = chords.iter().filter(|c| c.mapping.is_string()).count())]
+    // strings used by the mouse buttons are counted too
+    #[br(calc = chords.iter().filter(|c| c.mapping.is_string()).count()
+        + [mouse_left_click, mouse_middle_click, mouse_right_click].iter().filter(|m| **m & 0xFF == 0xFF).count())]
+    #[bw(ignore)]
     number_of_strings: usize,
 
     #[br(count = number_of_strings)]
     pub string_locations: Vec<u32>,
 
     #[br(count = number_of_strings)]
+    #[bw(map = |contents: &Vec<PosValue<StringContents>>| contents.iter().map(|c| c.val.clone()).collect::<Vec<_>>())]
     pub string_contents: Vec<PosValue<StringContents>>,
 }
 
-#[derive(BinRead)]
-#[br(little)]
+impl Config {
+    pub fn new() -> Self {
+        Self {
+            version: 5,
+            options_a: OptionsA::default().with_key_repeat(true),
+            number_of_chords: 0,
+            sleep_timeout: 600,
+            mouse_left_click: 0,
+            mouse_middle_click: 0,
+            mouse_right_click: 0,
+            mouse_accel_factor: 10,
+            key_repeat_delay: 100,
+            options_b: 0,
+            options_c: 0,
+            chords: vec![],
+            number_of_strings: 0,
+            string_locations: vec![],
+            string_contents: vec![],
+        }
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Append a string for chords to type, returns the index to use in
+    /// `ChordMapping::StringMapping(0xFF, index)`
+    pub fn push_string(&mut self, keys: Vec<ChordMapping>) -> usize {
+        self.string_contents.push(PosValue {
+            pos: 0,
+            val: StringContents {
+                // too long strings are caught when writing
+                size: u16::try_from((keys.len() + 1) * 2).unwrap_or(u16::MAX),
+                keys,
+            },
+        });
+        self.string_locations.push(0);
+
+        self.string_contents.len() - 1
+    }
+
+    /// What a mouse button does, None when it clicks as normal
+    pub fn mouse_action(&self, button: MouseButton) -> Option<ChordMapping> {
+        let [modifier, key] = match button {
+            MouseButton::Left => self.mouse_left_click,
+            MouseButton::Middle => self.mouse_middle_click,
+            MouseButton::Right => self.mouse_right_click,
+        }
+        .to_le_bytes();
+
+        match (modifier, key) {
+            (0, 0) => None,
+            (0xFF, index) => Some(ChordMapping::StringMapping(0xFF, index)),
+            (modifier, key) => Some(ChordMapping::KeyMapping(modifier, key)),
+        }
+    }
+
+    pub fn set_mouse_action(&mut self, button: MouseButton, mapping: Option<ChordMapping>) {
+        let value = match mapping {
+            None => 0,
+            Some(ChordMapping::StringMapping(modifier, index)) => {
+                u16::from_le_bytes([modifier, index])
+            }
+            Some(ChordMapping::KeyMapping(modifier, key)) => u16::from_le_bytes([modifier, key]),
+        };
+
+        match button {
+            MouseButton::Left => self.mouse_left_click = value,
+            MouseButton::Middle => self.mouse_middle_click = value,
+            MouseButton::Right => self.mouse_right_click = value,
+        }
+    }
+}
+
+#[binrw]
+#[brw(little)]
 #[derive(Debug)]
 pub struct Chord {
     chord: ButtonData,
     #[br(restore_position)]
+    #[bw(ignore)]
     modifier: u8,
     #[br(args { modifier })]
     pub mapping: ChordMapping,
 }
 
 impl Chord {
+    pub fn new(buttons: ButtonState, mapping: ChordMapping) -> Self {
+        Self {
+            chord: buttons.into(),
+            modifier: 0,
+            mapping,
+        }
+    }
+
     pub fn button_state(&self) -> ButtonState {
         self.chord.into()
     }
 }
 
-#[derive(Eq, PartialEq, Hash, Debug, BinRead)]
-#[br(little)]
+#[derive(Eq, PartialEq, Hash, Debug, Clone)]
+#[binrw]
+#[brw(little)]
 #[br(import { modifier: u8 })]
-pub(crate) enum ChordMapping {
+pub enum ChordMapping {
     #[br(assert(modifier == 0xFF))]
     StringMapping(u8, u8),
     KeyMapping(u8, u8),
@@ -66,8 +173,9 @@ impl ChordMapping {
     }
 }
 
-#[derive(Debug, BinRead)]
-#[br(little)]
+#[derive(Debug, Clone)]
+#[binrw]
+#[brw(little)]
 pub struct StringContents {
     size: u16,
 
@@ -76,33 +184,56 @@ pub struct StringContents {
 }
 
 #[bitfield]
-#[derive(BinRead, Debug, Copy, Clone)]
+#[derive(BinRead, BinWrite, Debug, Copy, Clone)]
 #[br(map = Self::from_bytes)]
 pub struct ButtonData {
-    num: bool,
-    a: bool,
-    e: bool,
-    sp: bool,
+    pub num: bool,
+    pub a: bool,
+    pub e: bool,
+    pub sp: bool,
 
-    alt: bool,
-    b: bool,
-    f: bool,
-    del: bool,
+    pub alt: bool,
+    pub b: bool,
+    pub f: bool,
+    pub del: bool,
 
-    ctrl: bool,
-    c: bool,
-    g: bool,
-    bs: bool,
+    pub ctrl: bool,
+    pub c: bool,
+    pub g: bool,
+    pub bs: bool,
 
-    shift: bool,
-    d: bool,
-    h: bool,
-    ent: bool,
+    pub shift: bool,
+    pub d: bool,
+    pub h: bool,
+    pub ent: bool,
+}
+
+impl From<ButtonState> for ButtonData {
+    fn from(state: ButtonState) -> Self {
+        ButtonData::new()
+            .with_num(state.t1)
+            .with_alt(state.t2)
+            .with_ctrl(state.t3)
+            .with_shift(state.t4)
+            .with_a(state.f1r)
+            .with_e(state.f1m)
+            .with_sp(state.f1l)
+            .with_b(state.f2r)
+            .with_f(state.f2m)
+            .with_del(state.f2l)
+            .with_c(state.f3r)
+            .with_g(state.f3m)
+            .with_bs(state.f3l)
+            .with_d(state.f4r)
+            .with_h(state.f4m)
+            .with_ent(state.f4l)
+    }
 }
 
 impl Into<ButtonState> for ButtonData {
     fn into(self) -> ButtonState {
         ButtonState {
+            t0: false,
             t1: self.num(),
             t2: self.alt(),
             t3: self.ctrl(),
@@ -126,13 +257,376 @@ impl Into<ButtonState> for ButtonData {
     }
 }
 
-pub(crate) fn parse<R: Read + Seek>(reader: &mut R) -> Result<Config, Box<dyn std::error::Error>> {
+pub fn parse<R: Read + Seek>(reader: &mut R) -> Result<Config, Error> {
     let res = Config::read(reader);
     match res {
-        Ok(config) => {
-            assert!(config.version == 5, "Not a version 5 config file");
+        Ok(mut config) => {
+            if config.version != 5 {
+                return Err(Error::parse(
+                    "v5",
+                    Location::Offset(0),
+                    format!("version is {}, not 5", config.version),
+                ));
+            }
+
+            // Order the contents by string index so they can be written back out in order
+            let mut string_contents = vec![];
+            for location in &config.string_locations {
+                let index = config
+                    .string_contents
+                    .iter()
+                    .position(|sc| sc.pos == *location as u64);
+                match index {
+                    Some(index) => string_contents.push(config.string_contents.remove(index)),
+                    None => {
+                        return Err(Error::parse(
+                            "v5",
+                            Location::Offset(*location as u64),
+                            "no string contents at the string location",
+                        ))
+                    }
+                }
+            }
+            config.string_contents = string_contents;
+
             Ok(config)
         }
-        Err(e) => Err(Box::new(e)),
+        Err(e) => Err(Error::binary_parse("v5", e, reader)),
+    }
+}
+
+/// The most chords the format can count, the firmware's own limits aren't documented
+pub const MAX_CHORDS: usize = u16::MAX as usize;
+/// Chords refer to strings with a u8 index
+pub const MAX_STRINGS: usize = u8::MAX as usize + 1;
+/// String sizes are a u16 count of bytes, including the size itself
+pub const MAX_STRING_KEYS: usize = (u16::MAX as usize - 2) / 2;
+
+fn limit_error(message: String) -> Error {
+    Error::Write(message)
+}
+
+pub fn write<W: Write + Seek>(mut config: Config, writer: &mut W) -> Result<(), Error> {
+    if config.chords.len() > MAX_CHORDS {
+        return Err(limit_error(format!(
+            "chord table exceeds {} entries ({} chords)",
+            MAX_CHORDS,
+            config.chords.len()
+        )));
+    }
+    if config.string_contents.len() > MAX_STRINGS {
+        return Err(limit_error(format!(
+            "string table exceeds {} entries ({} strings)",
+            MAX_STRINGS,
+            config.string_contents.len()
+        )));
+    }
+    if let Some(i) = config
+        .string_contents
+        .iter()
+        .position(|contents| contents.keys.len() > MAX_STRING_KEYS)
+    {
+        return Err(limit_error(format!(
+            "string {} exceeds {} keys ({} keys)",
+            i,
+            MAX_STRING_KEYS,
+            config.string_contents[i].keys.len()
+        )));
+    }
+
+    // update number of chords
+    config.number_of_chords = config.chords.len() as u16;
+    config.number_of_strings = config.string_contents.len();
+
+    // strings are stored right after the chord table and the string locations
+    let mut pos =
+        HEADER_SIZE + config.chords.len() as u32 * 4 + config.number_of_strings as u32 * 4;
+
+    config.string_locations.clear();
+    for contents in config.string_contents.iter_mut() {
+        contents.val.size = (contents.keys.len() as u16 + 1) * 2;
+        contents.pos = pos as u64;
+        config.string_locations.push(pos);
+        pos += contents.size as u32;
+    }
+
+    Config::write(&config, writer).map_err(Error::binary_write)?;
+
+    Ok(())
+}
+
+/// What a mapping types, strings are looked up in the string table
+fn mapping_action(config: &Config, mapping: &ChordMapping) -> Result<Action, Error> {
+    match *mapping {
+        ChordMapping::KeyMapping(modifier, key_code) => Ok(Action::Key { modifier, key_code }),
+        ChordMapping::StringMapping(_, index) => {
+            let contents = config
+                .string_locations
+                .get(index as usize)
+                .and_then(|string_pos| {
+                    config
+                        .string_contents
+                        .iter()
+                        .find(|sc| sc.pos == (*string_pos).into())
+                });
+            let Some(contents) = contents else {
+                return Err(Error::Conversion(format!(
+                    "v5 string {} has no contents",
+                    index
+                )));
+            };
+
+            let mut actions = vec![];
+            for c in &contents.keys {
+                if let ChordMapping::KeyMapping(modifier, key_code) = *c {
+                    actions.push(Action::Key { modifier, key_code });
+                }
+            }
+
+            Ok(Action::String(actions))
+        }
+    }
+}
+
+/// Turn a v5 config into a layout, returning a warning for everything that's
+/// dropped or changed on the way
+pub fn to_layout(config: &Config) -> Result<(Layout, Vec<String>), Error> {
+    let mut warnings = vec![];
+
+    let options = config.options_a;
+    if config.options_b != 0 || config.options_c != 0 {
+        warnings.push(format!(
+            "v5 options B {:#04x} and C {:#04x} aren't understood, ignoring them",
+            config.options_b, config.options_c
+        ));
+    }
+
+    let settings = Settings {
+        key_repeat: options.key_repeat(),
+        direct: options.direct_key(),
+        sticky_num: options.sticky_num(),
+        sticky_shift: options.sticky_shift(),
+        left_click: options.left_click(),
+        bluetooth_off: options.bluetooth_off(),
+        idle_time: std::time::Duration::from_secs(config.sleep_timeout as u64),
+        // same scale as the v6/v7 mouse speed (see format.md), kept as is so v5
+        // configs are written back unchanged, v6 and v7 clamp it
+        mouse_speed: config.mouse_accel_factor,
+        repeat_delay: Settings::repeat_delay_from_steps(clamp_setting(
+            "key repeat delay",
+            config.key_repeat_delay as u32,
+            1,
+            250,
+            &mut warnings,
+        )),
+        ..Default::default()
+    };
+
+    let mut chords = vec![];
+    for c in &config.chords {
+        chords.push(layout::Chord {
+            buttons: c.button_state(),
+            action: mapping_action(config, &c.mapping)?,
+        });
+    }
+
+    // each mouse button becomes a chord on its F0 button
+    for button in [MouseButton::Left, MouseButton::Middle, MouseButton::Right] {
+        let action = match config.mouse_action(button) {
+            Some(mapping) => mapping_action(config, &mapping)?,
+            None => Action::Mouse(layout::Mouse::click(button)),
+        };
+
+        chords.push(layout::Chord {
+            buttons: button.button_state(),
+            action,
+        });
+    }
+
+    Ok((Layout { settings, chords }, warnings))
+}
+
+/// Turn a layout into a v5 config, returning a warning for everything v5 can't store.
+///
+/// v5 devices have no T0 or F0 row and no system commands, chords using them are dropped.
+/// The exception is a chord on a single F0 button, which sets what that mouse button does.
+pub fn from_layout(layout: &Layout) -> (Config, Vec<String>) {
+    let mut config = Config::new();
+    let mut warnings = vec![];
+
+    let settings = &layout.settings;
+    config.options_a = config
+        .options_a
+        .with_key_repeat(settings.key_repeat)
+        .with_direct_key(settings.direct)
+        .with_left_click(settings.left_click)
+        .with_bluetooth_off(settings.bluetooth_off)
+        .with_sticky_num(settings.sticky_num)
+        .with_sticky_shift(settings.sticky_shift);
+    config.sleep_timeout = settings.idle_time_secs(&mut warnings);
+    config.key_repeat_delay = settings.repeat_delay_steps(&mut warnings);
+    // no conversion, see format.md
+    config.mouse_accel_factor = settings.mouse_speed;
+
+    if settings.sticky_alt || settings.sticky_ctrl {
+        warnings.push("sticky alt and sticky ctrl have no v5 equivalent".to_owned());
+    }
+
+    for (i, chord) in layout.chords.iter().enumerate() {
+        let button_state = chord.buttons;
+        // single F0 buttons set what the v5 mouse buttons do
+        let mouse_button = MouseButton::from_button_state(&button_state);
+        if mouse_button.is_none()
+            && (button_state.t0 || button_state.f0l || button_state.f0m || button_state.f0r)
+        {
+            warnings.push(format!(
+                "chord {} uses T0 or F0 buttons which v5 can't represent, skipping",
+                i + 1
+            ));
+            continue;
+        }
+
+        let mapping = match &chord.action {
+            Action::Key { modifier, key_code } => ChordMapping::KeyMapping(*modifier, *key_code),
+            Action::String(_) => {
+                let mut keys = vec![];
+                for action in chord.action.flatten() {
+                    match action {
+                        Action::Key { modifier, key_code } => {
+                            keys.push(ChordMapping::KeyMapping(*modifier, *key_code))
+                        }
+                        action => warnings.push(format!(
+                            "chord {} has {} in its string which v5 can't represent, skipping it",
+                            i + 1,
+                            action
+                        )),
+                    }
+                }
+
+                // checked before adding it so skipped chords don't leave their strings behind
+                if config.string_contents.len() >= MAX_STRINGS {
+                    warnings.push(format!(
+                        "chord {} needs a string but v5 only has room for {}, skipping",
+                        i + 1,
+                        MAX_STRINGS
+                    ));
+                    continue;
+                }
+
+                let index = config.push_string(keys);
+                ChordMapping::StringMapping(0xFF, index as u8)
+            }
+            Action::Mouse(mouse)
+                if mouse_button.is_some_and(|button| *mouse == layout::Mouse::click(button)) =>
+            {
+                // a normal click is the v5 default
+                continue;
+            }
+            action => {
+                warnings.push(format!(
+                    "chord {} does {} which v5 can't represent, skipping",
+                    i + 1,
+                    action
+                ));
+                continue;
+            }
+        };
+
+        match mouse_button {
+            Some(button) => config.set_mouse_action(button, Some(mapping)),
+            None => config.chords.push(Chord::new(button_state, mapping)),
+        }
+    }
+
+    (config, warnings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write() {
+        let mut file = std::fs::File::open("configs/backspice2_v5.cfg").unwrap();
+        let conf = parse(&mut file).unwrap();
+        assert!(conf.number_of_chords == 258);
+        assert!(conf.sleep_timeout == 3600);
+        assert!(conf.options_a.key_repeat() == true);
+        assert!(conf.string_contents.len() == conf.string_locations.len());
+
+        let mut output = std::io::Cursor::new(vec![]);
+        write(conf, &mut output).unwrap();
+
+        let expected = std::fs::read("configs/backspice2_v5.cfg").unwrap();
+        assert!(output.into_inner() == expected);
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut conf = Config::new();
+        for _ in 0..MAX_STRINGS + 1 {
+            conf.push_string(vec![ChordMapping::KeyMapping(0, 0x04)]);
+        }
+        let err = write(conf, &mut std::io::Cursor::new(vec![])).unwrap_err();
+        assert!(
+            err.to_string()
+                == "Failed to write config: string table exceeds 256 entries (257 strings)"
+        );
+
+        let mut conf = Config::new();
+        conf.push_string(vec![ChordMapping::KeyMapping(0, 0x04); MAX_STRING_KEYS + 1]);
+        let err = write(conf, &mut std::io::Cursor::new(vec![])).unwrap_err();
+        assert!(
+            err.to_string() == "Failed to write config: string 0 exceeds 32766 keys (32767 keys)"
+        );
+
+        // chords past the string table are skipped without leaving their strings behind
+        let mut layout = Layout::new();
+        for _ in 0..MAX_STRINGS + 2 {
+            layout.chords.push(layout::Chord {
+                buttons: ButtonState {
+                    f1l: true,
+                    ..Default::default()
+                },
+                action: Action::keys(&[(0, 0x04), (0, 0x05)]),
+            });
+        }
+        let (conf, warnings) = from_layout(&layout);
+        assert!(conf.string_contents.len() == MAX_STRINGS);
+        assert!(
+            warnings
+                == vec![
+                    "chord 257 needs a string but v5 only has room for 256, skipping".to_owned(),
+                    "chord 258 needs a string but v5 only has room for 256, skipping".to_owned(),
+                ]
+        );
+        write(conf, &mut std::io::Cursor::new(vec![])).unwrap();
+    }
+
+    #[test]
+    fn test_from_layout_warnings() {
+        let f1l = ButtonState {
+            f1l: true,
+            ..Default::default()
+        };
+        let layout = Layout::new()
+            .with_chord(
+                f1l,
+                Action::String(vec![
+                    Action::key(0, 0x04),
+                    Action::Delay(std::time::Duration::from_millis(50)),
+                ]),
+            )
+            .with_chord(f1l, Action::Mouse(layout::Mouse::click(MouseButton::Right)));
+
+        let (conf, warnings) = from_layout(&layout);
+        assert!(conf.chords.len() == 1);
+        assert!(
+            warnings
+                == vec![
+                    "chord 1 has Delay 50ms in its string which v5 can't represent, skipping it",
+                    "chord 2 does Mouse right click which v5 can't represent, skipping",
+                ]
+        );
     }
 }
@@ -3,73 +3,220 @@ use std::{
     io::{Read, Seek, SeekFrom, Write},
 };
 
-use binrw::{binrw, BinRead, BinResult, BinWrite, Endian};
-use modular_bitfield::{bitfield, prelude::B4};
-use std::convert::From;
+use binrw::{binrw, helpers::until_eof, BinRead, BinWrite};
+use modular_bitfield::{
+    bitfield,
+    prelude::{B4, B7},
+};
 
-use crate::{buttons::ButtonState, hid};
+use crate::{
+    binary::{
+        self, read_command_lists, write_command_lists, BinaryConfig, Header, ListChord, ListCommand,
+    },
+    buttons::ButtonState,
+    error::Error,
+    layout::{Action, Layout},
+};
 
-#[derive(Debug, Eq, PartialEq, Clone)]
-#[binrw]
-#[brw(big, repr = u8)]
-pub enum CommandType {
-    None = 0,
-    System = 1,
-    Keyboard = 2,
-    Mouse = 3,
-    Delay = 5,
-    ListOfCommands = 7,
-}
+// commands are the same as v7 apart from their padding
+pub use crate::binary::{
+    CommandList, CommandType, DelayCommand, HidCommand, MouseButtons, MouseCommand, MAX_CHORDS,
+    MAX_COMMAND_LIST_BYTES, MAX_FILE_SIZE,
+};
+pub use crate::layout::{MouseAction, SystemCommand};
 
 #[bitfield]
 #[derive(BinRead, BinWrite, Debug, Copy, Clone, Default)]
 #[br(map = Self::from_bytes)]
 pub struct ConfigFlags {
-    repeat_delay_enable: bool,
-    haptic: bool,
-    left_mouse_pos: bool, // FOL or FOR
-    direct: bool,
-    sticky_num: bool,
-    sticky_alt: bool,
-    sticky_ctrl: bool,
-    sticky_shift: bool,
+    pub repeat_delay_enable: bool,
+    pub haptic: bool,
+    pub left_mouse_pos: bool, // FOL or FOR
+    pub direct: bool,
+    pub sticky_num: bool,
+    pub sticky_alt: bool,
+    pub sticky_ctrl: bool,
+    pub sticky_shift: bool,
+}
+
+/// Settings byte at 0x13, the Tuner clears bit 0 when haptic feedback is turned
+/// off, the haptic bit in `ConfigFlags` is never set by it
+#[bitfield]
+#[derive(BinRead, BinWrite, Debug, Copy, Clone, Default)]
+#[br(map = Self::from_bytes)]
+pub struct DeviceFlags {
+    pub haptic: bool,
+    pub unknown: B7, // always 1 so far
 }
 
 #[binrw]
 #[brw(little)]
 #[derive(Debug)]
 pub struct Config {
-    #[brw(pad_before = 0x4)]
+    pub(crate) reserved0: [u8; 4],
     version: u8,
-    flags: ConfigFlags,
+    pub flags: ConfigFlags,
     pub number_of_chords: u16,
-    #[brw(pad_before = 0x4)]
     pub idle_time: u16,
+    // 0x0A-0x0F, always zero so far
+    pub(crate) reserved: [u8; 6],
     pub mouse_sensitivity: u8,
     pub key_repeat_delay: u8,
+    pub(crate) reserved2: u8,
+    pub device_flags: DeviceFlags,
+    /// Mouse speed for each step the joystick is pushed, same as v7
+    pub mouse_accel_table: [u8; 20],
     #[brw(seek_before = SeekFrom::Start(0x28))]
     #[br(count = number_of_chords)]
     pub chords: Vec<Chord>,
 
-    #[br(count = chords.iter().filter(|c| c.command.command_type == CommandType::ListOfCommands).count())]
-    pub command_lists: Vec<CommandList>,
+    /// One list per list chord, in chord order
+    #[br(parse_with = read_command_lists, args(&chords))]
+    #[bw(write_with = write_command_lists, args(chords))]
+    pub command_lists: Vec<CommandList<Command>>,
+
+    // anything after the last command list, kept so files are written back unchanged
+    #[br(parse_with = until_eof)]
+    pub(crate) trailing: Vec<u8>,
 }
 
 impl Config {
     pub fn new() -> Self {
         Self {
+            reserved0: [0; 4],
             version: 6,
-            flags: ConfigFlags::default()
-                .with_haptic(true)
-                .with_repeat_delay_enable(true),
+            // the Tuner only sets the device haptic flag
+            flags: ConfigFlags::default().with_repeat_delay_enable(true),
             number_of_chords: 0,
             chords: vec![],
             command_lists: vec![],
+            trailing: vec![],
             idle_time: 600,
+            reserved: [0; 6],
             mouse_sensitivity: 0x7f,
-            key_repeat_delay: 127,
+            key_repeat_delay: 100,
+            reserved2: 0,
+            device_flags: DeviceFlags::new().with_haptic(true).with_unknown(1),
+            mouse_accel_table: [
+                0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0C, 0x0D, 0x0F,
+                0x11, 0x14, 0x16, 0x18, 0x1A, 0x1D,
+            ],
+        }
+    }
+
+    /// Mouse speed as shown on the device, 1 is slowest and 254 fastest.
+    /// It's stored inverted like v7
+    pub fn mouse_speed(&self) -> u8 {
+        255 - self.mouse_sensitivity
+    }
+
+    pub fn set_mouse_speed(&mut self, speed: u8) {
+        self.mouse_sensitivity = 255 - speed;
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    pub fn with_flags(mut self, flags: ConfigFlags) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    /// Seconds before the device sleeps
+    pub fn with_idle_time(mut self, seconds: u16) -> Self {
+        self.idle_time = seconds;
+        self
+    }
+
+    pub fn with_mouse_speed(mut self, speed: u8) -> Self {
+        self.set_mouse_speed(speed);
+        self
+    }
+
+    /// Key repeat delay in 10ms steps
+    pub fn with_key_repeat_delay(mut self, steps: u8) -> Self {
+        self.key_repeat_delay = steps;
+        self
+    }
+
+    /// Add a chord, use `with_list_chord` for chords that run a command list
+    pub fn with_chord(mut self, chord: Chord) -> Self {
+        self.chords.push(chord);
+        self
+    }
+
+    /// Add a chord that runs `commands` one after another
+    pub fn with_list_chord(mut self, buttons: ButtonState, commands: Vec<Command>) -> Self {
+        self.chords.push(Chord::new(buttons, Command::list()));
+        self.command_lists.push(CommandList(commands));
+        self
+    }
+}
+
+impl BinaryConfig for Config {
+    type Command = Command;
+    type Chord = Chord;
+
+    const NAME: &'static str = "v6";
+
+    fn header(&self) -> Header {
+        let flags = self.flags;
+        Header {
+            key_repeat: flags.repeat_delay_enable(),
+            // the Tuner only ever changes the device flag
+            haptic: self.device_flags.haptic(),
+            direct: flags.direct(),
+            sticky_num: flags.sticky_num(),
+            sticky_alt: flags.sticky_alt(),
+            sticky_ctrl: flags.sticky_ctrl(),
+            sticky_shift: flags.sticky_shift(),
+            left_mouse_pos: flags.left_mouse_pos(),
+            idle_time: self.idle_time,
+            mouse_speed: self.mouse_speed(),
+            key_repeat_delay: self.key_repeat_delay,
+            mouse_accel_table: self.mouse_accel_table,
         }
     }
+
+    fn set_header(&mut self, header: &Header) {
+        let flags = &mut self.flags;
+        flags.set_repeat_delay_enable(header.key_repeat);
+        flags.set_direct(header.direct);
+        flags.set_sticky_num(header.sticky_num);
+        flags.set_sticky_alt(header.sticky_alt);
+        flags.set_sticky_ctrl(header.sticky_ctrl);
+        flags.set_sticky_shift(header.sticky_shift);
+        flags.set_left_mouse_pos(header.left_mouse_pos);
+        self.device_flags.set_haptic(header.haptic);
+
+        self.idle_time = header.idle_time;
+        self.set_mouse_speed(header.mouse_speed);
+        self.key_repeat_delay = header.key_repeat_delay;
+        self.mouse_accel_table = header.mouse_accel_table;
+    }
+
+    fn chords(&self) -> &[Chord] {
+        &self.chords
+    }
+
+    fn command_lists(&self) -> &[CommandList<Command>] {
+        &self.command_lists
+    }
+
+    fn take_chord_table(&mut self) -> (Vec<Chord>, Vec<CommandList<Command>>) {
+        (
+            std::mem::take(&mut self.chords),
+            std::mem::take(&mut self.command_lists),
+        )
+    }
+
+    fn set_chord_table(&mut self, chords: Vec<Chord>, command_lists: Vec<CommandList<Command>>) {
+        // the count is checked against MAX_CHORDS when writing
+        self.number_of_chords = chords.len() as u16;
+        self.chords = chords;
+        self.command_lists = command_lists;
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -81,7 +228,40 @@ pub struct Chord {
     pub command: Command,
 }
 
-#[derive(Debug, Clone)]
+impl Chord {
+    pub fn new(buttons: ButtonState, command: Command) -> Self {
+        Self {
+            buttons: buttons.into(),
+            command,
+        }
+    }
+
+    pub fn button_state(&self) -> ButtonState {
+        self.buttons.into()
+    }
+}
+
+impl ListChord for Chord {
+    type Command = Command;
+
+    fn new(buttons: ButtonState, command: Command) -> Self {
+        Chord::new(buttons, command)
+    }
+
+    fn button_state(&self) -> ButtonState {
+        self.button_state()
+    }
+
+    fn command(&self) -> &Command {
+        &self.command
+    }
+
+    fn command_mut(&mut self) -> &mut Command {
+        &mut self.command
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 #[binrw]
 #[brw(little)]
 pub struct Command {
@@ -90,97 +270,99 @@ pub struct Command {
     pub data: CommandData,
 }
 
-#[derive(Debug, Clone)]
+impl Command {
+    pub fn keyboard(modifier: u8, key_code: u8) -> Self {
+        Self {
+            command_type: CommandType::Keyboard,
+            data: CommandData::Keyboard(HidCommand { modifier, key_code }, 0),
+        }
+    }
+
+    pub fn system(command: SystemCommand) -> Self {
+        Self {
+            command_type: CommandType::System,
+            data: CommandData::System(command, 0, 0),
+        }
+    }
+
+    pub fn mouse(command: MouseCommand) -> Self {
+        Self {
+            command_type: CommandType::Mouse,
+            data: CommandData::Mouse(command, 0),
+        }
+    }
+
+    pub fn delay(delay: DelayCommand) -> Self {
+        Self {
+            command_type: CommandType::Delay,
+            data: CommandData::Delay(delay, 0),
+        }
+    }
+
+    /// Runs a command list, the offset is filled in when the config is written
+    pub fn list() -> Self {
+        Self {
+            command_type: CommandType::ListOfCommands,
+            data: CommandData::ListOfCommands(0, 0),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 #[binrw]
 #[br(little)]
 #[br(import { command_type: &CommandType })]
-pub(crate) enum CommandData {
+pub enum CommandData {
     #[br(assert(*command_type == CommandType::ListOfCommands))]
     ListOfCommands(u8, u16),
     #[br(assert(*command_type == CommandType::Keyboard))]
     Keyboard(HidCommand, u8),
     #[br(assert(*command_type == CommandType::System))]
-    System(u8, u8, u8),
+    System(
+        #[br(map = |code: u8| SystemCommand::from(code))]
+        #[bw(map = |command: &SystemCommand| u8::from(*command))]
+        SystemCommand,
+        u8,
+        u8,
+    ),
+    #[br(assert(*command_type == CommandType::Mouse))]
+    Mouse(MouseCommand, u8),
+    #[br(assert(*command_type == CommandType::Delay))]
+    Delay(DelayCommand, u8),
     #[br(assert(*command_type == CommandType::None))]
     None(u8, u8, u8),
 }
 
-#[derive(Debug, Clone)]
-#[binrw]
-pub struct HidCommand {
-    pub modifier: u8,
-    pub key_code: u8,
-}
-
-#[derive(Default, Debug)]
-pub struct CommandList(pub Vec<Command>);
-
-impl BinRead for CommandList {
-    type Args<'a> = ();
-
-    fn read_options<R: Read + Seek>(
-        reader: &mut R,
-        endian: Endian,
-        (): Self::Args<'_>,
-    ) -> BinResult<Self> {
-        let mut values = vec![];
-
-        loop {
-            let command = <Command>::read_options(reader, endian, ())?;
-            if command.command_type == CommandType::None {
-                return Ok(Self(values));
-            }
-            values.push(command);
-        }
-    }
-}
-
-impl BinWrite for CommandList {
-    type Args<'a> = ();
-
-    fn write_options<W: Write + Seek>(
-        &self,
-        writer: &mut W,
-        endian: Endian,
-        args: Self::Args<'_>,
-    ) -> BinResult<()> {
-        self.0.write_options(writer, endian, args)?;
-        0u32.write_options(writer, endian, args)?;
-
-        Ok(())
-    }
-}
-
 #[bitfield]
 #[derive(BinRead, BinWrite, Debug, Copy, Clone)]
 #[br(map = Self::from_bytes)]
 pub struct ButtonData {
-    t1: bool,
-    f1r: bool,
-    f1m: bool,
-    f1l: bool,
-
-    t2: bool,
-    f2r: bool,
-    f2m: bool,
-    f2l: bool,
-
-    t3: bool,
-    f3r: bool,
-    f3m: bool,
-    f3l: bool,
-
-    t4: bool,
-    f4r: bool,
-    f4m: bool,
-    f4l: bool,
-
-    t0: bool,
-    f0r: bool,
-    f0m: bool,
-    f0l: bool,
-
-    unknown: B4,
+    pub t1: bool,
+    pub f1r: bool,
+    pub f1m: bool,
+    pub f1l: bool,
+
+    pub t2: bool,
+    pub f2r: bool,
+    pub f2m: bool,
+    pub f2l: bool,
+
+    pub t3: bool,
+    pub f3r: bool,
+    pub f3m: bool,
+    pub f3l: bool,
+
+    pub t4: bool,
+    pub f4r: bool,
+    pub f4m: bool,
+    pub f4l: bool,
+
+    pub f0l: bool,
+    pub f0m: bool,
+    pub f0r: bool,
+    pub t0: bool,
+
+    pub unknown: B4,
 }
 
 impl From<ButtonState> for ButtonData {
@@ -189,7 +371,7 @@ impl From<ButtonState> for ButtonData {
             .with_f0l(state.f0l)
             .with_f0m(state.f0m)
             .with_f0r(state.f0r)
-            .with_t0(false)
+            .with_t0(state.t0)
             .with_t3(state.t3)
             .with_f3r(state.f3r)
             .with_f3m(state.f3m)
@@ -215,7 +397,7 @@ impl From<&ButtonState> for ButtonData {
             .with_f0l(state.f0l)
             .with_f0m(state.f0m)
             .with_f0r(state.f0r)
-            .with_t0(false)
+            .with_t0(state.t0)
             .with_t3(state.t3)
             .with_f3r(state.f3r)
             .with_f3m(state.f3m)
@@ -238,6 +420,7 @@ impl From<&ButtonState> for ButtonData {
 impl Into<ButtonState> for ButtonData {
     fn into(self) -> ButtonState {
         ButtonState {
+            t0: self.t0(),
             t1: self.t1(),
             t2: self.t2(),
             t3: self.t3(),
@@ -261,108 +444,117 @@ impl Into<ButtonState> for ButtonData {
     }
 }
 
-pub(crate) fn parse<R: Read + Seek>(reader: &mut R) -> Result<Config, Box<dyn std::error::Error>> {
-    let res = Config::read(reader);
-    match res {
-        Ok(config) => {
-            //println!("{:?}", config);
-            return Ok(config);
-        }
-        Err(e) => Err(Box::new(e)),
-    }
+pub fn parse<R: Read + Seek>(reader: &mut R) -> Result<Config, Error> {
+    Config::read(reader).map_err(|e| Error::binary_parse("v6", e, reader))
 }
 
-pub(crate) fn write<W: Write + Seek>(
-    mut config: Config,
-    writer: &mut W,
-    gen_caps: Option<i32>,
-) -> std::io::Result<()> {
-    // Generate chords for caps
-    if let Some(caps) = gen_caps {
-        let mut new_chords = vec![];
-        for chord in &config.chords {
-            if chord.command.command_type == CommandType::Keyboard
-                && chord.buttons.t0() == false
-                && chord.buttons.t1() == false
-                && chord.buttons.t2() == false
-                && chord.buttons.t3() == false
-                && chord.buttons.t4() == false
-            {
-                if let CommandData::Keyboard(hid_command, _) = &chord.command.data {
-                    if hid::ALPHA_HID_CODES.contains(&hid_command.key_code) {
-                        let mut chord = chord.clone();
-
-                        match caps {
-                            1 => chord.buttons.set_t1(true),
-                            2 => chord.buttons.set_t2(true),
-                            3 => chord.buttons.set_t3(true),
-                            4 => chord.buttons.set_t4(true),
-                            _ => {}
-                        }
-
-                        chord.command.data = CommandData::Keyboard(
-                            HidCommand {
-                                key_code: hid_command.key_code,
-                                modifier: hid_command.modifier | 0x2, //  Add left shift
-                            },
-                            0,
-                        );
-
-                        new_chords.push(chord);
-                    }
-                }
-            }
-        }
+pub fn write<W: Write + Seek>(config: Config, writer: &mut W) -> Result<Vec<String>, Error> {
+    binary::write(config, writer)
+}
 
-        if new_chords.len() > 0 {
-            println!("Adding {} uppercase chords", new_chords.len());
-            config.chords.append(&mut new_chords);
-        }
+/// Write a config exactly as it was read, chords, offsets and unknown bytes included
+pub fn write_lossless<W: Write + Seek>(config: &Config, writer: &mut W) -> Result<(), Error> {
+    config.write(writer).map_err(Error::binary_write)
+}
+
+impl ListCommand for Command {
+    fn is_end(&self) -> bool {
+        self.command_type == CommandType::None
     }
 
-    // update number of chords
-    config.number_of_chords = config.chords.len() as u16;
-
-    // update offsets in config
-    let command_lists_command_count = config
-        .chords
-        .iter()
-        .filter(|c| c.command.command_type == CommandType::ListOfCommands)
-        .count();
-    assert!(
-        command_lists_command_count == config.command_lists.len(),
-        "Commands with CommandType::ListOfCommands count mismatch"
-    );
-
-    let mut offset = 0;
-
-    let mut j = 0;
-    for i in 0..config.chords.len() {
-        if config.chords[i].command.command_type == CommandType::ListOfCommands {
-            let size = config.command_lists[j].0.len() * 4;
-            config.chords[i].command.data = CommandData::ListOfCommands(0, offset);
-            offset += size as u16;
-            offset += 4; // 0u32
-            j += 1;
+    fn list_offset(&self) -> Option<u16> {
+        match self.data {
+            CommandData::ListOfCommands(_, offset) => Some(offset),
+            _ => None,
         }
     }
 
-    let res = Config::write(&config, writer);
-    match res {
-        Ok(_) => {
-            println!("Wrote config");
+    fn list_at(offset: u16) -> Self {
+        Self {
+            command_type: CommandType::ListOfCommands,
+            data: CommandData::ListOfCommands(0, offset),
         }
-        Err(e) => {
-            println!("{:?}", e);
+    }
+
+    fn action(&self) -> Action {
+        match &self.data {
+            CommandData::Keyboard(hid_command, _) => Action::Key {
+                modifier: hid_command.modifier,
+                key_code: hid_command.key_code,
+            },
+            CommandData::System(command, _, _) => Action::System(*command),
+            CommandData::Mouse(mouse_command, _) => Action::Mouse(mouse_command.into()),
+            CommandData::Delay(delay, _) => {
+                Action::Delay(std::time::Duration::from_millis(delay.millis() as u64))
+            }
+            CommandData::ListOfCommands(_, _) => Action::String(vec![]),
+            CommandData::None(_, _, _) => Action::None,
         }
     }
 
-    // TODO: Figure out more config format details
-    writer.seek(SeekFrom::Start(0x13));
-    let data = hex::decode("03000102030405060708090A0C0D0F111416181A1D").unwrap();
-    writer.write(&data)?;
+    fn from_action(action: &Action) -> Result<Self, Error> {
+        let (command_type, data) = match action {
+            Action::Key { modifier, key_code } => (
+                CommandType::Keyboard,
+                CommandData::Keyboard(
+                    HidCommand {
+                        modifier: *modifier,
+                        key_code: *key_code,
+                    },
+                    0,
+                ),
+            ),
+            Action::String(_) => (
+                CommandType::ListOfCommands,
+                CommandData::ListOfCommands(0, 0),
+            ),
+            Action::Mouse(mouse) => (CommandType::Mouse, CommandData::Mouse(mouse.into(), 0)),
+            Action::Delay(delay) => (
+                CommandType::Delay,
+                CommandData::Delay(
+                    DelayCommand::from_millis(
+                        u32::try_from(delay.as_millis()).unwrap_or(u32::MAX),
+                    )?,
+                    0,
+                ),
+            ),
+            Action::System(command) => (CommandType::System, CommandData::System(*command, 0, 0)),
+            Action::None => (CommandType::None, CommandData::None(0, 0, 0)),
+        };
+
+        Ok(Command { command_type, data })
+    }
+}
 
-    Ok(())
+/// Turn a v6 config into a layout, returning a warning for every bit it doesn't understand
+pub fn to_layout(config: &Config) -> (Layout, Vec<String>) {
+    let mut warnings = vec![];
+    // every config seen so far has 1 here
+    let unknown = config.device_flags.unknown();
+    if unknown != 1 {
+        warnings.push(format!(
+            "v6 device flag bits 1-7 ({:#04x}) aren't understood, dropping them",
+            unknown
+        ));
+    }
+    for (i, chord) in config.chords.iter().enumerate() {
+        if chord.buttons.unknown() != 0 {
+            warnings.push(format!(
+                "chord {} has unknown button bits {:#03x} set, dropping them",
+                i + 1,
+                chord.buttons.unknown()
+            ));
+        }
+    }
+    (binary::to_layout(config), warnings)
+}
+
+/// Turn a layout into a v6 config, returning a warning for everything v6 can't store.
+///
+/// Buttons go through `ButtonState` since v6 and v7 lay out the T0/F0 bits
+/// differently (F0L and F0R trade places).
+pub fn from_layout(layout: &Layout) -> Result<(Config, Vec<String>), Error> {
+    binary::from_layout(Config::new(), layout)
 }
 
 #[cfg(test)]
@@ -374,6 +566,7 @@ mod tests {
         let mut file = std::fs::File::open("test/configs/v6/haptic_off.cfg").unwrap();
         let conf = Config::read(&mut file).unwrap();
         assert!(conf.flags.haptic() == false);
+        assert!(conf.device_flags.haptic() == false);
 
         let mut file = std::fs::File::open("test/configs/v6/sticky_alt.cfg").unwrap();
         let conf = Config::read(&mut file).unwrap();
@@ -404,10 +597,102 @@ mod tests {
         assert!(conf.flags.left_mouse_pos() == false);
         assert!(conf.flags.repeat_delay_enable() == true);
         assert!(conf.flags.haptic() == false);
+        assert!(conf.device_flags.haptic() == true);
+        assert!(conf.device_flags.unknown() == 1);
+        assert!(conf.mouse_accel_table == Config::new().mouse_accel_table);
         assert!(conf.flags.sticky_alt() == false);
         assert!(conf.flags.sticky_num() == false);
         assert!(conf.flags.sticky_shift() == false);
         assert!(conf.flags.sticky_ctrl() == false);
         assert!(conf.flags.repeat_delay_enable() == true);
+        assert!(conf.idle_time == 600);
+        assert!(conf.mouse_sensitivity == 0x7f);
+        assert!(conf.key_repeat_delay == 100);
+        assert!(conf.chords.len() == 8);
+        assert!(conf.chords.iter().any(|c| matches!(
+            c.command.data,
+            CommandData::System(SystemCommand::ConfigCycle, _, _)
+        )));
+
+        // bits we don't understand are dropped with a warning, chords counted from 1
+        let (_, warnings) = to_layout(&conf);
+        assert!(warnings.is_empty());
+        let mut conf = conf;
+        conf.device_flags.set_unknown(0x3);
+        conf.chords[1].buttons.set_unknown(0x2);
+        let (_, warnings) = to_layout(&conf);
+        assert!(
+            warnings
+                == vec![
+                    "v6 device flag bits 1-7 (0x03) aren't understood, dropping them",
+                    "chord 2 has unknown button bits 0x2 set, dropping them",
+                ]
+        );
+
+        let mut file = std::fs::File::open("test/configs/v6/idle_time_10min.cfg").unwrap();
+        let conf = Config::read(&mut file).unwrap();
+        assert!(conf.idle_time == 3600);
+
+        let mut file = std::fs::File::open("test/configs/v6/mouse_sensitivity_1.cfg").unwrap();
+        let conf = Config::read(&mut file).unwrap();
+        assert!(conf.mouse_sensitivity == 0xFE);
+
+        let mut file = std::fs::File::open("test/configs/v6/key_repeat_delay_10ms.cfg").unwrap();
+        let conf = Config::read(&mut file).unwrap();
+        assert!(conf.key_repeat_delay == 1);
+    }
+
+    #[test]
+    fn test_rewrite_header() {
+        // re-saving keeps every header byte the Tuner wrote
+        for entry in std::fs::read_dir("test/configs/v6").unwrap() {
+            let path = entry.unwrap().path();
+            let original = std::fs::read(&path).unwrap();
+            let conf = Config::read(&mut std::io::Cursor::new(&original)).unwrap();
+
+            let mut output = std::io::Cursor::new(vec![]);
+            write(conf, &mut output).unwrap();
+            assert!(output.get_ref()[..0x28] == original[..0x28], "{:?}", path);
+        }
+
+        let mut output = std::io::Cursor::new(vec![]);
+        write(Config::new(), &mut output).unwrap();
+        let empty = std::fs::read("test/configs/v6/empty.cfg").unwrap();
+        assert!(output.get_ref()[0x0A..0x28] == empty[0x0A..0x28]);
+    }
+
+    #[test]
+    fn test_command_list_offsets() {
+        let list_chord = |offset| Chord {
+            buttons: ButtonData::new().with_f1l(true),
+            command: Command {
+                command_type: CommandType::ListOfCommands,
+                data: CommandData::ListOfCommands(0, offset),
+            },
+        };
+        let a = CommandList(vec![Command {
+            command_type: CommandType::Keyboard,
+            data: CommandData::Keyboard(
+                HidCommand {
+                    modifier: 0,
+                    key_code: 0x04,
+                },
+                0,
+            ),
+        }]);
+
+        // both chords point at the same list
+        let mut conf = Config::new();
+        conf.chords = vec![list_chord(0), list_chord(0)];
+        conf.number_of_chords = 2;
+        conf.command_lists = vec![a.clone(), a.clone()];
+
+        let mut output = std::io::Cursor::new(vec![]);
+        write_lossless(&conf, &mut output).unwrap();
+        assert!(output.get_ref().len() == 0x28 + 2 * 8 + 8);
+
+        output.set_position(0);
+        let read = Config::read(&mut output).unwrap();
+        assert!(read.command_lists == vec![a.clone(), a]);
     }
 }
@@ -3,52 +3,81 @@ use std::{
     io::{Read, Seek, SeekFrom, Write},
 };
 
-use binrw::{binrw, BinRead, BinResult, BinWrite, Endian};
+use binrw::{binrw, helpers::until_eof, BinRead, BinWrite};
 use modular_bitfield::{
     bitfield,
     prelude::{B1, B4, B7},
 };
 
 use crate::{
-    buttons::{self, ButtonState},
-    hid,
+    binary::{
+        self, read_command_lists, write_command_lists, BinaryConfig, Header, ListChord, ListCommand,
+    },
+    buttons::ButtonState,
+    error::Error,
+    layout::{Action, Layout},
 };
 
+// shared with v6, and the layout so every version means the same thing by them
+pub use crate::binary::{
+    CommandList, CommandType, DelayCommand, HidCommand, MouseButtons, MouseCommand, MAX_CHORDS,
+    MAX_COMMAND_LIST_BYTES, MAX_FILE_SIZE,
+};
+pub use crate::layout::{MouseAction, SystemCommand};
+
 #[bitfield]
 #[derive(BinRead, BinWrite, Debug, Copy, Clone, Default)]
 #[br(map = Self::from_bytes)]
 pub struct ConfigFlags {
-    repeat_delay_enable: bool,
-    unknown: B1, // maybe bluetooth?
-    haptic: bool,
-    direct: bool,
-    sticky_num: bool,
-    sticky_alt: bool,
-    sticky_ctrl: bool,
-    sticky_shift: bool,
-    left_mouse_pos: bool, // FOL or FOR
-    unknown2: B7,         // future expansion??
+    pub repeat_delay_enable: bool,
+    pub unknown: B1, // maybe bluetooth?
+    pub haptic: bool,
+    pub direct: bool,
+    pub sticky_num: bool,
+    pub sticky_alt: bool,
+    pub sticky_ctrl: bool,
+    pub sticky_shift: bool,
+    pub left_mouse_pos: bool, // FOL or FOR
+    pub unknown2: B7,         // future expansion??
 }
 
 #[binrw]
 #[brw(little)]
 #[derive(Debug)]
 pub struct Config {
-    #[brw(pad_before = 0x4)]
+    pub(crate) reserved0: [u8; 4],
     version: u8,
-    flags: ConfigFlags,
-    #[brw(pad_before = 0x1)]
+    pub flags: ConfigFlags,
+    pub(crate) reserved1: u8,
     pub number_of_chords: u16,
     pub idle_time: u16,
     pub mouse_sensitivity: u8,
     pub key_repeat_delay: u8,
 
+    // 0x0E-0x43, always zero so far
+    pub(crate) reserved: [u8; 0x36],
+    /// Looks like the modes nav mode cycle steps through, always 3 1 2 so far
+    pub nav_modes: [u32; 3],
+    /// Always 0A 0B 09 09 so far, meaning unknown
+    pub unknown_settings: [u8; 4],
+    pub(crate) reserved2: [u8; 12],
+    /// Mouse speed for each step the joystick is pushed, rises from 0 to 0x1D
+    pub mouse_accel_table: [u8; 20],
+    /// Always 0x80 (the middle of a u8) so far, maybe calibration
+    pub unknown_table: [u8; 12],
+
     #[brw(seek_before = SeekFrom::Start(0x80))]
     #[br(count = number_of_chords)]
     pub chords: Vec<Chord>,
 
-    #[br(count = chords.iter().filter(|c| c.command.command_type == CommandType::ListOfCommands).count())]
-    pub command_lists: Vec<CommandList>,
+    /// One list per list chord, in chord order
+    #[br(parse_with = read_command_lists, args(&chords))]
+    #[bw(write_with = write_command_lists, args(chords))]
+    pub command_lists: Vec<CommandList<Command>>,
+
+    // anything after the last command list, kept so files are written back unchanged
+    #[br(parse_with = until_eof)]
+    pub(crate) trailing: Vec<u8>,
 }
 
 #[derive(Debug, Clone)]
@@ -60,19 +89,20 @@ pub struct Chord {
     pub command: Command,
 }
 
-#[derive(Debug, Eq, PartialEq, Clone)]
-#[binrw]
-#[brw(big, repr = u8)]
-pub enum CommandType {
-    None = 0,
-    System = 1,
-    Keyboard = 2,
-    Mouse = 3,
-    Delay = 5,
-    ListOfCommands = 7,
+impl Chord {
+    pub fn new(buttons: ButtonState, command: Command) -> Self {
+        Self {
+            buttons: buttons.into(),
+            command,
+        }
+    }
+
+    pub fn button_state(&self) -> ButtonState {
+        self.buttons.into()
+    }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 #[binrw]
 #[brw(little)]
 pub struct Command {
@@ -82,97 +112,98 @@ pub struct Command {
     pub data: CommandData,
 }
 
+impl Command {
+    pub fn keyboard(modifier: u8, key_code: u8) -> Self {
+        Self {
+            command_type: CommandType::Keyboard,
+            data: CommandData::Keyboard(HidCommand { modifier, key_code }),
+        }
+    }
+
+    pub fn system(command: SystemCommand) -> Self {
+        Self {
+            command_type: CommandType::System,
+            data: CommandData::System(command, 0),
+        }
+    }
+
+    pub fn mouse(command: MouseCommand) -> Self {
+        Self {
+            command_type: CommandType::Mouse,
+            data: CommandData::Mouse(command),
+        }
+    }
+
+    pub fn delay(delay: DelayCommand) -> Self {
+        Self {
+            command_type: CommandType::Delay,
+            data: CommandData::Delay(delay),
+        }
+    }
+
+    /// Runs a command list, the offset is filled in when the config is written
+    pub fn list() -> Self {
+        Self {
+            command_type: CommandType::ListOfCommands,
+            data: CommandData::ListOfCommands(0),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 #[binrw]
 #[br(little)]
 #[br(import { command_type: &CommandType })]
-pub(crate) enum CommandData {
+pub enum CommandData {
     #[br(assert(*command_type == CommandType::ListOfCommands))]
     ListOfCommands(u16),
     #[br(assert(*command_type == CommandType::Keyboard))]
     Keyboard(HidCommand),
     #[br(assert(*command_type == CommandType::System))]
-    System(u8, u8),
+    System(
+        #[br(map = |code: u8| SystemCommand::from(code))]
+        #[bw(map = |command: &SystemCommand| u8::from(*command))]
+        SystemCommand,
+        u8,
+    ),
+    #[br(assert(*command_type == CommandType::Mouse))]
+    Mouse(MouseCommand),
+    #[br(assert(*command_type == CommandType::Delay))]
+    Delay(DelayCommand),
     #[br(assert(*command_type == CommandType::None))]
     None(u8, u8),
 }
 
-#[derive(Debug, Clone, PartialEq)]
-#[binrw]
-pub struct HidCommand {
-    pub modifier: u8,
-    pub key_code: u8,
-}
-
-#[derive(Default, Debug)]
-pub struct CommandList(pub Vec<Command>);
-
-impl BinRead for CommandList {
-    type Args<'a> = ();
-
-    fn read_options<R: Read + Seek>(
-        reader: &mut R,
-        endian: Endian,
-        (): Self::Args<'_>,
-    ) -> BinResult<Self> {
-        let mut values = vec![];
-
-        loop {
-            let command = <Command>::read_options(reader, endian, ())?;
-            if command.command_type == CommandType::None {
-                return Ok(Self(values));
-            }
-            values.push(command);
-        }
-    }
-}
-
-impl BinWrite for CommandList {
-    type Args<'a> = ();
-
-    fn write_options<W: Write + Seek>(
-        &self,
-        writer: &mut W,
-        endian: Endian,
-        args: Self::Args<'_>,
-    ) -> BinResult<()> {
-        self.0.write_options(writer, endian, args)?;
-        0u32.write_options(writer, endian, args)?;
-
-        Ok(())
-    }
-}
-
 #[bitfield]
 #[derive(BinRead, BinWrite, Debug, Copy, Clone, PartialEq)]
 #[br(map = Self::from_bytes)]
 pub struct ButtonData {
-    t1: bool,
-    f1r: bool,
-    f1m: bool,
-    f1l: bool,
-
-    t2: bool,
-    f2r: bool,
-    f2m: bool,
-    f2l: bool,
-
-    t3: bool,
-    f3r: bool,
-    f3m: bool,
-    f3l: bool,
-
-    t4: bool,
-    f4r: bool,
-    f4m: bool,
-    f4l: bool,
-
-    f0r: bool,
-    f0m: bool,
-    f0l: bool,
-    t0: bool,
-
-    unknown: B4,
+    pub t1: bool,
+    pub f1r: bool,
+    pub f1m: bool,
+    pub f1l: bool,
+
+    pub t2: bool,
+    pub f2r: bool,
+    pub f2m: bool,
+    pub f2l: bool,
+
+    pub t3: bool,
+    pub f3r: bool,
+    pub f3m: bool,
+    pub f3l: bool,
+
+    pub t4: bool,
+    pub f4r: bool,
+    pub f4m: bool,
+    pub f4l: bool,
+
+    pub f0r: bool,
+    pub f0m: bool,
+    pub f0l: bool,
+    pub t0: bool,
+
+    pub unknown: B4,
 }
 
 impl From<ButtonState> for ButtonData {
@@ -181,7 +212,7 @@ impl From<ButtonState> for ButtonData {
             .with_f0l(state.f0l)
             .with_f0m(state.f0m)
             .with_f0r(state.f0r)
-            .with_t0(false)
+            .with_t0(state.t0)
             .with_t3(state.t3)
             .with_f3r(state.f3r)
             .with_f3m(state.f3m)
@@ -207,7 +238,7 @@ impl From<&ButtonState> for ButtonData {
             .with_f0l(state.f0l)
             .with_f0m(state.f0m)
             .with_f0r(state.f0r)
-            .with_t0(false)
+            .with_t0(state.t0)
             .with_t3(state.t3)
             .with_f3r(state.f3r)
             .with_f3m(state.f3m)
@@ -230,6 +261,7 @@ impl From<&ButtonState> for ButtonData {
 impl Into<ButtonState> for ButtonData {
     fn into(self) -> ButtonState {
         ButtonState {
+            t0: self.t0(),
             t1: self.t1(),
             t2: self.t2(),
             t3: self.t3(),
@@ -256,181 +288,268 @@ impl Into<ButtonState> for ButtonData {
 impl Config {
     pub fn new() -> Self {
         Self {
+            reserved0: [0; 4],
             version: 7,
             flags: ConfigFlags::default()
                 .with_haptic(true)
                 .with_repeat_delay_enable(true),
+            reserved1: 0,
             number_of_chords: 0,
             idle_time: 600,
             mouse_sensitivity: 0x7f,
             key_repeat_delay: 100,
+            reserved: [0; 0x36],
+            nav_modes: [3, 1, 2],
+            unknown_settings: [0x0A, 0x0B, 0x09, 0x09],
+            reserved2: [0; 12],
+            mouse_accel_table: [
+                0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0C, 0x0D, 0x0F,
+                0x11, 0x14, 0x16, 0x18, 0x1A, 0x1D,
+            ],
+            unknown_table: [0x80; 12],
             chords: vec![],
             command_lists: vec![],
+            trailing: vec![],
         }
     }
+
+    /// Mouse speed as shown on the device, 1 is slowest and 254 fastest.
+    /// It's stored inverted so 0xFE is a speed of 1
+    pub fn mouse_speed(&self) -> u8 {
+        255 - self.mouse_sensitivity
+    }
+
+    pub fn set_mouse_speed(&mut self, speed: u8) {
+        self.mouse_sensitivity = 255 - speed;
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    pub fn with_flags(mut self, flags: ConfigFlags) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    /// Seconds before the device sleeps
+    pub fn with_idle_time(mut self, seconds: u16) -> Self {
+        self.idle_time = seconds;
+        self
+    }
+
+    pub fn with_mouse_speed(mut self, speed: u8) -> Self {
+        self.set_mouse_speed(speed);
+        self
+    }
+
+    /// Key repeat delay in 10ms steps
+    pub fn with_key_repeat_delay(mut self, steps: u8) -> Self {
+        self.key_repeat_delay = steps;
+        self
+    }
+
+    /// Add a chord, use `with_list_chord` for chords that run a command list
+    pub fn with_chord(mut self, chord: Chord) -> Self {
+        self.chords.push(chord);
+        self
+    }
+
+    /// Add a chord that runs `commands` one after another
+    pub fn with_list_chord(mut self, buttons: ButtonState, commands: Vec<Command>) -> Self {
+        self.chords.push(Chord::new(buttons, Command::list()));
+        self.command_lists.push(CommandList(commands));
+        self
+    }
 }
 
-pub(crate) fn parse<R: Read + Seek>(reader: &mut R) -> Result<Config, Box<dyn std::error::Error>> {
-    let res = Config::read(reader);
-    match res {
-        Ok(config) => {
-            //println!("{:?}", config);
-            return Ok(config);
-        }
-        Err(e) => Err(Box::new(e)),
+impl ListChord for Chord {
+    type Command = Command;
+
+    fn new(buttons: ButtonState, command: Command) -> Self {
+        Chord::new(buttons, command)
+    }
+
+    fn button_state(&self) -> ButtonState {
+        self.button_state()
+    }
+
+    fn command(&self) -> &Command {
+        &self.command
+    }
+
+    fn command_mut(&mut self) -> &mut Command {
+        &mut self.command
     }
 }
 
-pub(crate) fn write<W: Write + Seek>(
-    mut config: Config,
-    writer: &mut W,
-    gen_caps: Option<i32>,
-    ensure_system_chords: bool,
-) -> std::io::Result<()> {
-    if let Some(t_key) = gen_caps {
-        config.generate_caps(t_key);
-    }
-
-    if ensure_system_chords {
-        config.ensure_system_chords();
-    }
-
-    // update number of chords
-    config.number_of_chords = config.chords.len() as u16;
-
-    // update offsets in config
-    let command_lists_command_count = config
-        .chords
-        .iter()
-        .filter(|c| c.command.command_type == CommandType::ListOfCommands)
-        .count();
-    assert!(
-        command_lists_command_count == config.command_lists.len(),
-        "Commands with CommandType::ListOfCommands count mismatch"
-    );
-
-    let mut offset = 0;
-
-    let mut j = 0;
-    for i in 0..config.chords.len() {
-        if config.chords[i].command.command_type == CommandType::ListOfCommands {
-            let size = config.command_lists[j].0.len() * 4;
-            config.chords[i].command.data = CommandData::ListOfCommands(offset);
-            offset += size as u16;
-            offset += 4; // 0u32
-            j += 1;
+impl BinaryConfig for Config {
+    type Command = Command;
+    type Chord = Chord;
+
+    const NAME: &'static str = "v7";
+
+    fn header(&self) -> Header {
+        let flags = self.flags;
+        Header {
+            key_repeat: flags.repeat_delay_enable(),
+            haptic: flags.haptic(),
+            direct: flags.direct(),
+            sticky_num: flags.sticky_num(),
+            sticky_alt: flags.sticky_alt(),
+            sticky_ctrl: flags.sticky_ctrl(),
+            sticky_shift: flags.sticky_shift(),
+            left_mouse_pos: flags.left_mouse_pos(),
+            idle_time: self.idle_time,
+            mouse_speed: self.mouse_speed(),
+            key_repeat_delay: self.key_repeat_delay,
+            mouse_accel_table: self.mouse_accel_table,
         }
     }
 
-    let res = Config::write(&config, writer);
-    match res {
-        Ok(_) => {
-            println!("Wrote config");
-        }
-        Err(e) => {
-            println!("{:?}", e);
-        }
+    fn set_header(&mut self, header: &Header) {
+        let flags = &mut self.flags;
+        flags.set_repeat_delay_enable(header.key_repeat);
+        flags.set_haptic(header.haptic);
+        flags.set_direct(header.direct);
+        flags.set_sticky_num(header.sticky_num);
+        flags.set_sticky_alt(header.sticky_alt);
+        flags.set_sticky_ctrl(header.sticky_ctrl);
+        flags.set_sticky_shift(header.sticky_shift);
+        flags.set_left_mouse_pos(header.left_mouse_pos);
+
+        self.idle_time = header.idle_time;
+        self.set_mouse_speed(header.mouse_speed);
+        self.key_repeat_delay = header.key_repeat_delay;
+        self.mouse_accel_table = header.mouse_accel_table;
     }
 
-    // TODO: Figure out more config format details
-    writer.seek(SeekFrom::Start(0x44));
-    let data = hex::decode("0300000001000000020000000A0B0909000000000000000000000000000102030405060708090A0C0D0F111416181A1D808080808080808080808080").unwrap();
-    writer.write(&data)?;
+    fn chords(&self) -> &[Chord] {
+        &self.chords
+    }
+
+    fn command_lists(&self) -> &[CommandList<Command>] {
+        &self.command_lists
+    }
+
+    fn take_chord_table(&mut self) -> (Vec<Chord>, Vec<CommandList<Command>>) {
+        (
+            std::mem::take(&mut self.chords),
+            std::mem::take(&mut self.command_lists),
+        )
+    }
 
-    Ok(())
+    fn set_chord_table(&mut self, chords: Vec<Chord>, command_lists: Vec<CommandList<Command>>) {
+        // the count is checked against MAX_CHORDS when writing
+        self.number_of_chords = chords.len() as u16;
+        self.chords = chords;
+        self.command_lists = command_lists;
+    }
 }
 
-impl Config {
-    fn generate_caps(&mut self, t_key: i32) {
-        // Generate chords for caps
-
-        let mut new_chords = vec![];
-        for chord in &self.chords {
-            if chord.command.command_type == CommandType::Keyboard
-                && chord.buttons.t0() == false
-                && chord.buttons.t1() == false
-                && chord.buttons.t2() == false
-                && chord.buttons.t3() == false
-                && chord.buttons.t4() == false
-            {
-                if let CommandData::Keyboard(hid_command) = &chord.command.data {
-                    if hid::ALPHA_HID_CODES.contains(&hid_command.key_code) {
-                        let mut chord = chord.clone();
-
-                        match t_key {
-                            1 => chord.buttons.set_t1(true),
-                            2 => chord.buttons.set_t2(true),
-                            3 => chord.buttons.set_t3(true),
-                            4 => chord.buttons.set_t4(true),
-                            _ => {}
-                        }
-
-                        chord.command.data = CommandData::Keyboard(HidCommand {
-                            key_code: hid_command.key_code,
-                            modifier: hid_command.modifier | 0x2, //  Add left shift
-                        });
-
-                        new_chords.push(chord);
-                    }
-                }
-            }
+pub fn parse<R: Read + Seek>(reader: &mut R) -> Result<Config, Error> {
+    Config::read(reader).map_err(|e| Error::binary_parse("v7", e, reader))
+}
+
+pub fn write<W: Write + Seek>(config: Config, writer: &mut W) -> Result<Vec<String>, Error> {
+    binary::write(config, writer)
+}
+
+/// Write a config exactly as it was read, chords, offsets and unknown bytes included
+pub fn write_lossless<W: Write + Seek>(config: &Config, writer: &mut W) -> Result<(), Error> {
+    config.write(writer).map_err(Error::binary_write)
+}
+
+impl ListCommand for Command {
+    fn is_end(&self) -> bool {
+        self.command_type == CommandType::None
+    }
+
+    fn list_offset(&self) -> Option<u16> {
+        match self.data {
+            CommandData::ListOfCommands(offset) => Some(offset),
+            _ => None,
         }
+    }
 
-        if new_chords.len() > 0 {
-            println!("Adding {} uppercase chords", new_chords.len());
-            self.chords.append(&mut new_chords);
+    fn list_at(offset: u16) -> Self {
+        Self {
+            command_type: CommandType::ListOfCommands,
+            data: CommandData::ListOfCommands(offset),
         }
     }
 
-    fn ensure_system_chords(&mut self) {
-        let system_commands = vec![
-            CommandData::System(6, 0), // Bluetooth hosts: clear
-            CommandData::System(2, 0),  // LED: Keyboard Flags
-            CommandData::System(10, 0), // LED: Battery Level
-            CommandData::System(12, 0), // Print status to keyboard
-            CommandData::System(1, 0), // Sleep now
-            CommandData::System(5, 0), // Bluetooth hosts: cycle
-            CommandData::System(4, 0), // Config cycle
-            CommandData::System(11, 0), // Nav mode: cycle
-        ];
+    fn action(&self) -> Action {
+        match &self.data {
+            CommandData::Keyboard(hid_command) => Action::Key {
+                modifier: hid_command.modifier,
+                key_code: hid_command.key_code,
+            },
+            CommandData::System(command, _) => Action::System(*command),
+            CommandData::Mouse(mouse_command) => Action::Mouse(mouse_command.into()),
+            CommandData::Delay(delay) => {
+                Action::Delay(std::time::Duration::from_millis(delay.millis() as u64))
+            }
+            CommandData::ListOfCommands(_) => Action::String(vec![]),
+            CommandData::None(_, _) => Action::None,
+        }
+    }
 
-        let buttons = vec![
-            ButtonData::new()
-                .with_t1(true)
-                .with_t4(true)
-                .with_f4r(true),
-            ButtonData::new().with_f1l(true).with_t0(true),
-            ButtonData::new().with_f1m(true).with_t0(true),
-            ButtonData::new().with_f1r(true).with_t0(true),
-            ButtonData::new().with_t2(true).with_t3(true).with_t0(true),
-            ButtonData::new().with_f4r(true).with_t0(true),
-            ButtonData::new().with_f4m(true).with_t0(true),
-            ButtonData::new().with_f4l(true).with_t0(true),
-        ];
+    fn from_action(action: &Action) -> Result<Self, Error> {
+        let (command_type, data) = match action {
+            Action::Key { modifier, key_code } => (
+                CommandType::Keyboard,
+                CommandData::Keyboard(HidCommand {
+                    modifier: *modifier,
+                    key_code: *key_code,
+                }),
+            ),
+            Action::String(_) => (CommandType::ListOfCommands, CommandData::ListOfCommands(0)),
+            Action::Mouse(mouse) => (CommandType::Mouse, CommandData::Mouse(mouse.into())),
+            Action::Delay(delay) => (
+                CommandType::Delay,
+                CommandData::Delay(DelayCommand::from_millis(
+                    u32::try_from(delay.as_millis()).unwrap_or(u32::MAX),
+                )?),
+            ),
+            Action::System(command) => (CommandType::System, CommandData::System(*command, 0)),
+            Action::None => (CommandType::None, CommandData::None(0, 0)),
+        };
+
+        Ok(Command { command_type, data })
+    }
+}
 
-        for (command, button) in system_commands.iter().zip(buttons.iter()) {
-            let mut found = false;
-            for chord in &self.chords {
-                if chord.command.command_type == CommandType::System
-                    && chord.buttons == *button
-                    && chord.command.data == *command
-                {
-                    found = true;
-                    break;
-                }
-            }
+/// Turn a v7 config into a layout, returning a warning for every bit it doesn't understand
+pub fn to_layout(config: &Config) -> (Layout, Vec<String>) {
+    let mut warnings = vec![];
 
-            if !found {
-                self.chords.push(Chord {
-                    buttons: button.clone(),
-                    command: Command {
-                        command_type: CommandType::System,
-                        data: command.clone(),
-                    },
-                });
-            }
+    let flags = config.flags;
+    if flags.unknown() != 0 {
+        warnings.push("v7 config flag bit 1 is set but isn't understood, dropping it".to_owned());
+    }
+    if flags.unknown2() != 0 {
+        warnings.push(format!(
+            "v7 config flag bits 9-15 ({:#04x}) aren't understood, dropping them",
+            flags.unknown2()
+        ));
+    }
+    for (i, chord) in config.chords.iter().enumerate() {
+        if chord.buttons.unknown() != 0 {
+            warnings.push(format!(
+                "chord {} has unknown button bits {:#03x} set, dropping them",
+                i + 1,
+                chord.buttons.unknown()
+            ));
         }
     }
+
+    (binary::to_layout(config), warnings)
+}
+
+/// Turn a layout into a v7 config, returning a warning for everything v7 can't store
+pub fn from_layout(layout: &Layout) -> Result<(Config, Vec<String>), Error> {
+    binary::from_layout(Config::new(), layout)
 }
 
 #[cfg(test)]
@@ -485,19 +604,292 @@ mod tests {
         assert!(conf.mouse_sensitivity == 0x7f);
         assert!(conf.key_repeat_delay == 100);
 
-        // print system chords from more_system
-        let mut file = std::fs::File::open("test/configs/v7/more_system.cfg").unwrap();
+        let mut file = std::fs::File::open("test/configs/v7/mouse_sensitivity_1.cfg").unwrap();
         let conf = Config::read(&mut file).unwrap();
-        for chord in &conf.chords {
-            if chord.command.command_type == CommandType::System {
-                println!("{:?}", chord);
-            }
-        }
+        assert!(conf.mouse_speed() == 1);
 
         let mut file = std::fs::File::open("test/configs/v7/default.cfg").unwrap();
         let conf = Config::read(&mut file).unwrap();
         assert!(conf.number_of_chords == 157);
         assert!(conf.chords.len() == 157);
         assert!(conf.chords[0].buttons.f1r() == true);
+
+        // the Tuner's system chords, every command it knows about
+        let commands = conf
+            .chords
+            .iter()
+            .enumerate()
+            .filter_map(|(i, c)| match c.command.data {
+                CommandData::System(command, _) => Some((i, command)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert!(
+            commands
+                == vec![
+                    (108, SystemCommand::BluetoothHostsClear),
+                    (150, SystemCommand::LedKeyboardFlags),
+                    (151, SystemCommand::LedBatteryLevel),
+                    (152, SystemCommand::PrintStatus),
+                    (153, SystemCommand::Sleep),
+                    (154, SystemCommand::BluetoothHostsCycle),
+                    (155, SystemCommand::ConfigCycle),
+                    (156, SystemCommand::NavModeCycle),
+                ]
+        );
+    }
+
+    #[test]
+    fn test_mouse() {
+        // "_ 0M left mouse" from format.md
+        let bytes = vec![0x00, 0x00, 0x02, 0x00, 0x03, 0x01, 0x00, 0x00];
+        let chord = Chord::read(&mut std::io::Cursor::new(&bytes)).unwrap();
+        assert!(chord.buttons.f0m() == true);
+        assert!(chord.command.command_type == CommandType::Mouse);
+        let CommandData::Mouse(mouse_command) = &chord.command.data else {
+            panic!("not a mouse command");
+        };
+        assert!(mouse_command.buttons.left() == true);
+        assert!(mouse_command.buttons.right() == false);
+        assert!(mouse_command.action == MouseAction::Click);
+        assert!(mouse_command.is_release() == false);
+
+        let mut output = std::io::Cursor::new(vec![]);
+        chord.write(&mut output).unwrap();
+        assert!(output.into_inner() == bytes);
+
+        // press, delay, release list from format.md, the release has no buttons
+        let release = Command::read(&mut std::io::Cursor::new([0x03, 0x00, 0x00, 0x00])).unwrap();
+        assert!(release.data == CommandData::Mouse(MouseCommand::click(MouseButtons::new())));
+        let CommandData::Mouse(mouse_command) = &release.data else {
+            panic!("not a mouse command");
+        };
+        assert!(mouse_command.is_release() == true);
+    }
+
+    #[test]
+    fn test_delay() {
+        // E8 03 is 10 seconds
+        let delay = Command::read(&mut std::io::Cursor::new([0x05, 0xE8, 0x03, 0x00])).unwrap();
+        assert!(delay.data == CommandData::Delay(DelayCommand::from_millis(10_000).unwrap()));
+
+        assert!(DelayCommand::from_millis(50).unwrap().millis() == 50);
+        assert!(DelayCommand::from_millis(DelayCommand::MAX_MILLIS).is_ok());
+        assert!(DelayCommand::from_millis(DelayCommand::MAX_MILLIS + 10).is_err());
+        assert!(DelayCommand::from_millis(15).is_err());
+
+        // press, delay, release list from format.md
+        let bytes = vec![
+            0x03, 0x01, 0x00, 0x00, 0x05, 0x05, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00,
+        ];
+        let list = CommandList::<Command>::read_le(&mut std::io::Cursor::new(&bytes)).unwrap();
+        assert!(list.0.len() == 3);
+        assert!(list.0[1].data == CommandData::Delay(DelayCommand::from_millis(50).unwrap()));
+
+        let mut output = std::io::Cursor::new(vec![]);
+        list.write_le(&mut output).unwrap();
+        assert!(output.into_inner() == bytes);
+    }
+
+    #[test]
+    fn test_system() {
+        let mut file = std::fs::File::open("test/configs/v7/empty_w_system.cfg").unwrap();
+        let mut conf = Config::read(&mut file).unwrap();
+        let commands = conf
+            .chords
+            .iter()
+            .filter_map(|c| match c.command.data {
+                CommandData::System(command, _) => Some(command),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert!(commands.len() == 8);
+        assert!(commands.contains(&SystemCommand::ConfigCycle));
+        assert!(commands.contains(&SystemCommand::NavModeCycle));
+
+        // nothing to add when they're all there already
+        let (mut layout, warnings) = to_layout(&conf);
+        assert!(warnings.is_empty());
+        layout.ensure_system_chords();
+        assert!(layout.chords.len() == 8);
+
+        // bits we don't understand are dropped with a warning, chords counted from 1
+        conf.chords[1].buttons.set_unknown(0x3);
+        let (_, warnings) = to_layout(&conf);
+        assert!(warnings == vec!["chord 2 has unknown button bits 0x3 set, dropping them"]);
+
+        // codes we don't know about are written back unchanged
+        let bytes = [0x01, 0xC8, 0x00, 0x00];
+        let command = Command::read(&mut std::io::Cursor::new(bytes)).unwrap();
+        assert!(command.data == CommandData::System(SystemCommand::Unknown(0xC8), 0));
+        let mut output = std::io::Cursor::new(vec![]);
+        command.write(&mut output).unwrap();
+        assert!(output.into_inner() == bytes);
+    }
+
+    #[test]
+    fn test_header_tables() {
+        let original = std::fs::read("test/configs/v7/default.cfg").unwrap();
+        let mut conf = Config::read(&mut std::io::Cursor::new(&original)).unwrap();
+        assert!(conf.nav_modes == [3, 1, 2]);
+        assert!(conf.unknown_settings == [0x0A, 0x0B, 0x09, 0x09]);
+        assert!(conf.mouse_accel_table == Config::new().mouse_accel_table);
+        assert!(conf.unknown_table == [0x80; 12]);
+
+        // new configs get the same header as the device writes
+        let mut output = std::io::Cursor::new(vec![]);
+        write(Config::new(), &mut output).unwrap();
+        assert!(output.get_ref()[0x0E..0x80] == original[0x0E..0x80]);
+
+        // and tables read from a file are written back instead of the defaults
+        conf.mouse_accel_table[19] = 0x30;
+        conf.nav_modes = [1, 2, 3];
+        let mut output = std::io::Cursor::new(vec![]);
+        write(conf, &mut output).unwrap();
+        output.set_position(0);
+        let conf = Config::read(&mut output).unwrap();
+        assert!(conf.mouse_accel_table[19] == 0x30);
+        assert!(conf.nav_modes == [1, 2, 3]);
+    }
+
+    #[test]
+    fn test_command_list_offsets() {
+        let key = |key_code| Command {
+            command_type: CommandType::Keyboard,
+            data: CommandData::Keyboard(HidCommand {
+                modifier: 0,
+                key_code,
+            }),
+        };
+        let list_chord = |offset| Chord {
+            buttons: ButtonData::new().with_f1l(true),
+            command: Command {
+                command_type: CommandType::ListOfCommands,
+                data: CommandData::ListOfCommands(offset),
+            },
+        };
+
+        // the first chord's list is stored second and the last two share one
+        let mut conf = Config::new();
+        conf.chords = vec![list_chord(8), list_chord(0), list_chord(0)];
+        conf.number_of_chords = 3;
+        let a = CommandList(vec![key(0x04)]);
+        let b = CommandList(vec![key(0x05)]);
+        conf.command_lists = vec![b.clone(), a.clone(), a.clone()];
+
+        let mut output = std::io::Cursor::new(vec![]);
+        write_lossless(&conf, &mut output).unwrap();
+        // two lists of one command and a terminator
+        assert!(output.get_ref().len() == 0x80 + 3 * 8 + 2 * 8);
+
+        output.set_position(0);
+        let read = Config::read(&mut output).unwrap();
+        assert!(read.command_lists == vec![b.clone(), a.clone(), a.clone()]);
+
+        let mut rewritten = std::io::Cursor::new(vec![]);
+        write_lossless(&read, &mut rewritten).unwrap();
+        assert!(rewritten.into_inner() == output.into_inner());
+
+        // chords can't share an offset but want different lists
+        conf.command_lists = vec![b.clone(), a.clone(), b.clone()];
+        assert!(write_lossless(&conf, &mut std::io::Cursor::new(vec![])).is_err());
+    }
+
+    #[test]
+    fn test_shared_command_lists() {
+        let list = |key_code| {
+            CommandList(vec![Command {
+                command_type: CommandType::Keyboard,
+                data: CommandData::Keyboard(HidCommand {
+                    modifier: 0,
+                    key_code,
+                }),
+            }])
+        };
+        let list_chord = || Chord {
+            buttons: ButtonData::new().with_f1l(true),
+            command: Command {
+                command_type: CommandType::ListOfCommands,
+                data: CommandData::ListOfCommands(0),
+            },
+        };
+
+        let mut conf = Config::new();
+        conf.chords = vec![list_chord(), list_chord(), list_chord()];
+        conf.command_lists = vec![list(0x04), list(0x05), list(0x04)];
+
+        let mut output = std::io::Cursor::new(vec![]);
+        let messages = write(conf, &mut output).unwrap();
+        assert!(messages == vec!["shared 1 identical command lists, saving 8 bytes"]);
+        // only two lists are stored
+        assert!(output.get_ref().len() == 0x80 + 3 * 8 + 2 * 8);
+
+        output.set_position(0);
+        let read = Config::read(&mut output).unwrap();
+        assert!(read.chords[0].command.data == read.chords[2].command.data);
+        assert!(read.chords[1].command.data == CommandData::ListOfCommands(8));
+        assert!(read.command_lists == vec![list(0x04), list(0x05), list(0x04)]);
+    }
+
+    #[test]
+    fn test_limits() {
+        let list_chord = || Chord {
+            buttons: ButtonData::new().with_f1l(true),
+            command: Command {
+                command_type: CommandType::ListOfCommands,
+                data: CommandData::ListOfCommands(0),
+            },
+        };
+        let key = |key_code| Command {
+            command_type: CommandType::Keyboard,
+            data: CommandData::Keyboard(HidCommand {
+                modifier: 0,
+                key_code,
+            }),
+        };
+
+        let mut conf = Config::new();
+        conf.chords = vec![list_chord(); MAX_CHORDS + 1];
+        conf.command_lists = vec![CommandList(vec![key(0x04)]); MAX_CHORDS + 1];
+        let err = write(conf, &mut std::io::Cursor::new(vec![])).unwrap_err();
+        assert!(
+            err.to_string()
+                == "Failed to write config: chord table exceeds 65535 entries (65536 chords)"
+        );
+
+        // the first two lists run past the range offsets can reach
+        let mut conf = Config::new();
+        conf.chords = vec![list_chord(), list_chord(), list_chord()];
+        conf.command_lists = vec![
+            CommandList(vec![key(0x04); 0x3FFE]),
+            CommandList(vec![key(0x05)]),
+            CommandList(vec![key(0x06)]),
+        ];
+        let err = write(conf, &mut std::io::Cursor::new(vec![])).unwrap_err();
+        assert!(
+            err.to_string()
+                == "Failed to write config: command lists exceed 65536 bytes at chord 2 (65540 bytes)"
+        );
+
+        // the first list fills the whole offset range so the second has nowhere to start
+        let mut conf = Config::new();
+        conf.chords = vec![list_chord(), list_chord()];
+        conf.command_lists = vec![
+            CommandList(vec![key(0x04); 0x3FFF]),
+            CommandList(vec![key(0x05)]),
+        ];
+        let err = write(conf, &mut std::io::Cursor::new(vec![])).unwrap_err();
+        assert!(
+            err.to_string() == "Failed to write config: command list offset overflow at chord 2"
+        );
+
+        let mut conf = Config::new();
+        conf.trailing = vec![0; MAX_FILE_SIZE];
+        let err = write(conf, &mut std::io::Cursor::new(vec![])).unwrap_err();
+        assert!(
+            err.to_string()
+                == "Failed to write config: config is 262272 bytes, over the 262144 byte limit"
+        );
     }
 }